@@ -0,0 +1,391 @@
+use crate::raymath::{Vec3, Ray3, Aabb, SamplingCfg, HitRay, HitRecord, HittableObject, Accelerator};
+
+// Test a set of primitives, narrowing cfg.t_max to the closest hit found.
+fn hit_prims(prims:&[HittableObject], indices:&[usize], r:&Ray3, cfg:&mut SamplingCfg, res:&mut Option<HitRecord>){
+    for &i in indices.iter() {
+        if let Some(hit) = prims[i].hit(r, *cfg) {
+            cfg.t_max = hit.t;
+            *res = Some(hit);
+        }
+    }
+}
+
+//
+// Uniform grid
+//
+// Cells per unit volume, scaled by the primitive count.
+const GRID_DENSITY : f64 = 3.0;
+const GRID_MAX_RES : usize = 128;
+// Primitives this many times larger than the median are kept out of the grid
+// (e.g. a ground sphere) and tested against every ray instead.
+const GRID_LARGE_FACTOR : f64 = 8.0;
+
+pub struct UniformGrid{
+    prims : Vec<HittableObject>,
+    overflow : Vec<usize>,
+    cells : Vec<Vec<usize>>,
+    dims : [usize; 3],
+    cell_size : Vec3,
+    grid_bounds : Aabb,
+    bounds : Aabb
+}
+
+impl UniformGrid{
+    fn cell_coord(&self, p:Vec3, a:usize) -> usize{
+        let c = ((p.axis(a) - self.grid_bounds.min.axis(a)) / self.cell_size.axis(a)) as isize;
+        c.clamp(0, self.dims[a] as isize - 1) as usize
+    }
+
+    fn cell_index(&self, c:[usize; 3]) -> usize{
+        (c[2] * self.dims[1] + c[1]) * self.dims[0] + c[0]
+    }
+}
+
+impl Accelerator for UniformGrid{
+    fn build(prims:Vec<HittableObject>) -> UniformGrid{
+        let boxes : Vec<Aabb> = prims.iter().map(|p| p.bounding_box()).collect();
+        let bounds = boxes.iter().fold(Aabb::empty(), |b, o| b.union(o));
+
+        let mut diags : Vec<f64> = boxes.iter().map(|b| b.diagonal().length()).collect();
+        diags.sort_by(|a, b| a.total_cmp(b));
+        let median = if diags.is_empty() {0.0} else {diags[diags.len() / 2]};
+        let is_large = |b:&Aabb| b.diagonal().length() > GRID_LARGE_FACTOR * median;
+
+        let mut overflow = vec![];
+        let mut grid_bounds = Aabb::empty();
+        for (i, b) in boxes.iter().enumerate() {
+            if is_large(b) {
+                overflow.push(i);
+            } else {
+                grid_bounds = grid_bounds.union(b);
+            }
+        }
+
+        let mut grid = UniformGrid{prims, overflow, cells:vec![], dims:[1, 1, 1], cell_size:Vec3::ones(), grid_bounds, bounds};
+        let gridded = boxes.len() - grid.overflow.len();
+        if gridded == 0 {
+            return grid;
+        }
+
+        let ext = grid_bounds.diagonal();
+        let eps = 1e-6;
+        let volume = ext.x.max(eps) * ext.y.max(eps) * ext.z.max(eps);
+        let per_unit = (GRID_DENSITY * gridded as f64 / volume).cbrt();
+        for a in 0 .. 3 {
+            grid.dims[a] = ((ext.axis(a) * per_unit).ceil() as usize).clamp(1, GRID_MAX_RES);
+        }
+        grid.cell_size = Vec3{
+            x:ext.x.max(eps) / grid.dims[0] as f64,
+            y:ext.y.max(eps) / grid.dims[1] as f64,
+            z:ext.z.max(eps) / grid.dims[2] as f64
+        };
+        grid.cells = vec![vec![]; grid.dims[0] * grid.dims[1] * grid.dims[2]];
+
+        for (i, b) in boxes.iter().enumerate() {
+            if is_large(b) {
+                continue;
+            }
+            let lo = [grid.cell_coord(b.min, 0), grid.cell_coord(b.min, 1), grid.cell_coord(b.min, 2)];
+            let hi = [grid.cell_coord(b.max, 0), grid.cell_coord(b.max, 1), grid.cell_coord(b.max, 2)];
+            for z in lo[2] ..= hi[2] {
+                for y in lo[1] ..= hi[1] {
+                    for x in lo[0] ..= hi[0] {
+                        let idx = grid.cell_index([x, y, z]);
+                        grid.cells[idx].push(i);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn bounding_box(&self) -> Aabb{self.bounds}
+    fn prims(&self) -> &[HittableObject]{&self.prims}
+}
+
+impl HitRay for UniformGrid{
+    fn hit(&self, r:&Ray3, mut cfg:SamplingCfg) -> Option<HitRecord>{
+        let mut res = None;
+        hit_prims(&self.prims, &self.overflow, r, &mut cfg, &mut res);
+        if self.cells.is_empty() {
+            return res;
+        }
+        let (t0, t1) = match self.grid_bounds.hit(r, cfg.t_min, cfg.t_max) {
+            Some(range) => range,
+            None => return res
+        };
+
+        // 3D DDA
+        let p = r.at(t0);
+        let mut cell = [0isize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [f64::MAX; 3];
+        let mut t_delta = [f64::MAX; 3];
+        for a in 0 .. 3 {
+            let c = self.cell_coord(p, a);
+            cell[a] = c as isize;
+            let d = r.dir.axis(a);
+            let cs = self.cell_size.axis(a);
+            let lo = self.grid_bounds.min.axis(a);
+            if d > 0.0 {
+                step[a] = 1;
+                t_next[a] = t0 + (lo + (c + 1) as f64 * cs - p.axis(a)) / d;
+                t_delta[a] = cs / d;
+            } else if d < 0.0 {
+                step[a] = -1;
+                t_next[a] = t0 + (lo + c as f64 * cs - p.axis(a)) / d;
+                t_delta[a] = -cs / d;
+            }
+        }
+
+        loop {
+            let idx = self.cell_index([cell[0] as usize, cell[1] as usize, cell[2] as usize]);
+            hit_prims(&self.prims, &self.cells[idx], r, &mut cfg, &mut res);
+
+            let a = if t_next[0] < t_next[1] && t_next[0] < t_next[2] {0} else if t_next[1] < t_next[2] {1} else {2};
+            let t_exit = t_next[a];
+            if cfg.t_max <= t_exit || t_exit > t1 {
+                break;
+            }
+            cell[a] += step[a];
+            if cell[a] < 0 || cell[a] >= self.dims[a] as isize {
+                break;
+            }
+            t_next[a] += t_delta[a];
+        }
+        res
+    }
+}
+
+//
+// SAH kd-tree
+//
+const KD_TRAVERSAL_COST : f64 = 1.0;
+const KD_ISECT_COST : f64 = 80.0;
+const KD_EMPTY_BONUS : f64 = 0.5;
+const KD_MAX_PRIMS : usize = 1;
+const KD_STACK_SIZE : usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+enum KdNode{
+    // The below child directly follows its parent
+    Interior{axis:usize, split:f64, above:usize},
+    Leaf{start:usize, count:usize}
+}
+
+pub struct KdTree{
+    prims : Vec<HittableObject>,
+    prim_indices : Vec<usize>,
+    nodes : Vec<KdNode>,
+    bounds : Aabb
+}
+
+#[derive(Debug, Copy, Clone)]
+struct KdEdge{
+    t : f64,
+    start : bool
+}
+
+impl KdTree{
+    fn make_leaf(&mut self, prims:&[usize]) -> usize{
+        self.nodes.push(KdNode::Leaf{start:self.prim_indices.len(), count:prims.len()});
+        self.prim_indices.extend_from_slice(prims);
+        self.nodes.len() - 1
+    }
+
+    fn build_node(&mut self, node_bounds:Aabb, boxes:&[Aabb], prims:Vec<usize>, depth:i32, mut bad_refines:i32) -> usize{
+        if prims.len() <= KD_MAX_PRIMS || depth == 0 {
+            return self.make_leaf(&prims);
+        }
+
+        // Find the cheapest split by the surface area heuristic
+        let old_cost = KD_ISECT_COST * prims.len() as f64;
+        let total_sa = node_bounds.surface_area();
+        let inv_total_sa = if total_sa > 0.0 {1.0 / total_sa} else {0.0};
+        let d = node_bounds.diagonal();
+        let mut best : Option<(usize, f64)> = None;
+        let mut best_cost = f64::MAX;
+        let mut axis = node_bounds.longest_axis();
+        for _ in 0 .. 3 {
+            let mut edges : Vec<KdEdge> = Vec::with_capacity(2 * prims.len());
+            for &p in prims.iter() {
+                edges.push(KdEdge{t:boxes[p].min.axis(axis), start:true});
+                edges.push(KdEdge{t:boxes[p].max.axis(axis), start:false});
+            }
+            edges.sort_by(|a, b| a.t.total_cmp(&b.t).then(b.start.cmp(&a.start)));
+
+            let other0 = d.axis((axis + 1) % 3);
+            let other1 = d.axis((axis + 2) % 3);
+            let lo = node_bounds.min.axis(axis);
+            let hi = node_bounds.max.axis(axis);
+            let mut n_below = 0;
+            let mut n_above = prims.len();
+            for e in edges.iter() {
+                if !e.start {
+                    n_above -= 1;
+                }
+                if e.t > lo && e.t < hi {
+                    let below_sa = 2.0 * (other0 * other1 + (e.t - lo) * (other0 + other1));
+                    let above_sa = 2.0 * (other0 * other1 + (hi - e.t) * (other0 + other1));
+                    let p_below = below_sa * inv_total_sa;
+                    let p_above = above_sa * inv_total_sa;
+                    let eb = if n_above == 0 || n_below == 0 {KD_EMPTY_BONUS} else {0.0};
+                    let cost = KD_TRAVERSAL_COST + KD_ISECT_COST * (1.0 - eb) * (p_below * n_below as f64 + p_above * n_above as f64);
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = Some((axis, e.t));
+                    }
+                }
+                if e.start {
+                    n_below += 1;
+                }
+            }
+            if best.is_some() {
+                break;
+            }
+            axis = (axis + 1) % 3;
+        }
+
+        if best_cost > old_cost {
+            bad_refines += 1;
+        }
+        let (axis, split) = match best {
+            Some(b) if !((best_cost > 4.0 * old_cost && prims.len() < 16) || bad_refines == 3) => b,
+            _ => return self.make_leaf(&prims)
+        };
+
+        // Primitives lying in the split plane go to both sides
+        let below : Vec<usize> = prims.iter().copied().filter(|&p| boxes[p].min.axis(axis) <= split).collect();
+        let above : Vec<usize> = prims.iter().copied().filter(|&p| boxes[p].max.axis(axis) >= split).collect();
+        let mut below_bounds = node_bounds;
+        let mut above_bounds = node_bounds;
+        match axis {
+            0 => {below_bounds.max.x = split; above_bounds.min.x = split;},
+            1 => {below_bounds.max.y = split; above_bounds.min.y = split;},
+            _ => {below_bounds.max.z = split; above_bounds.min.z = split;}
+        }
+
+        let node = self.nodes.len();
+        self.nodes.push(KdNode::Interior{axis, split, above:0});
+        self.build_node(below_bounds, boxes, below, depth - 1, bad_refines);
+        let above_node = self.build_node(above_bounds, boxes, above, depth - 1, bad_refines);
+        self.nodes[node] = KdNode::Interior{axis, split, above:above_node};
+        node
+    }
+}
+
+impl Accelerator for KdTree{
+    fn build(prims:Vec<HittableObject>) -> KdTree{
+        let boxes : Vec<Aabb> = prims.iter().map(|p| p.bounding_box()).collect();
+        let bounds = boxes.iter().fold(Aabb::empty(), |b, o| b.union(o));
+        let n = prims.len();
+        let mut tree = KdTree{prims, prim_indices:vec![], nodes:vec![], bounds};
+        // Traversal pushes at most one node per level
+        let max_depth = ((8.0 + 1.3 * (n.max(1) as f64).log2()).round() as i32).min(KD_STACK_SIZE as i32);
+        tree.build_node(bounds, &boxes, (0 .. n).collect(), max_depth, 0);
+        tree
+    }
+
+    fn bounding_box(&self) -> Aabb{self.bounds}
+    fn prims(&self) -> &[HittableObject]{&self.prims}
+}
+
+impl HitRay for KdTree{
+    fn hit(&self, r:&Ray3, mut cfg:SamplingCfg) -> Option<HitRecord>{
+        if self.prims.is_empty() {
+            return None;
+        }
+        let (mut t_min, mut t_max) = self.bounds.hit(r, cfg.t_min, cfg.t_max)?;
+        let mut res = None;
+        let mut stack = [(0usize, 0.0f64, 0.0f64); KD_STACK_SIZE];
+        let mut sp = 0;
+        let mut node = 0;
+        loop {
+            if cfg.t_max < t_min {
+                break;
+            }
+            match self.nodes[node] {
+                KdNode::Interior{axis, split, above} => {
+                    let o = r.orig.axis(axis);
+                    let d = r.dir.axis(axis);
+                    let t_plane = (split - o) / d;
+                    let below_first = o < split || (o == split && d <= 0.0);
+                    let (first, second) = if below_first {(node + 1, above)} else {(above, node + 1)};
+                    if t_plane > t_max || t_plane <= 0.0 {
+                        node = first;
+                    } else if t_plane < t_min {
+                        node = second;
+                    } else {
+                        stack[sp] = (second, t_plane, t_max);
+                        sp += 1;
+                        node = first;
+                        t_max = t_plane;
+                    }
+                },
+                KdNode::Leaf{start, count} => {
+                    hit_prims(&self.prims, &self.prim_indices[start .. start + count], r, &mut cfg, &mut res);
+                    if sp == 0 {
+                        break;
+                    }
+                    sp -= 1;
+                    (node, t_min, t_max) = stack[sp];
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::raymath::{vec3, mk_sphere, Ray3, SamplingCfg, HitRay, HittableObject, AccelKind};
+    use super::*;
+
+    fn scene(rng:&mut StdRng, kind:AccelKind) -> HittableObject{
+        let mut world = vec![mk_sphere(0.0, -1000.0, 0.0, 1000.0, 0)];
+        for i in 0 .. 200 {
+            let c = vec3(rng.gen_range(-10.0 .. 10.0), rng.gen_range(0.0 .. 3.0), rng.gen_range(-10.0 .. 10.0));
+            world.push(mk_sphere(c.x, c.y, c.z, rng.gen_range(0.1 .. 0.6), i + 1));
+        }
+        HittableObject::wrap_accel(kind, world)
+    }
+
+    #[test]
+    fn accelerators_agree_with_list(){
+        let build = |kind| scene(&mut StdRng::seed_from_u64(11), kind);
+        let (list, grid, tree) = (build(AccelKind::List), build(AccelKind::Grid), build(AccelKind::KdTree));
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0 .. 20000 {
+            let orig = vec3(rng.gen_range(-15.0 .. 15.0), rng.gen_range(0.1 .. 6.0), rng.gen_range(-15.0 .. 15.0));
+            let dir = vec3(rng.gen_range(-1.0 .. 1.0), rng.gen_range(-1.0 .. 1.0), rng.gen_range(-1.0 .. 1.0));
+            let r = Ray3::new(orig, dir);
+            let cfg = SamplingCfg::new(0.001, f64::INFINITY);
+            let expected = list.hit(&r, cfg).map(|h| (h.mat, h.t));
+            for obj in [&grid, &tree] {
+                let got = obj.hit(&r, cfg).map(|h| (h.mat, h.t));
+                assert_eq!(got.map(|h| h.0), expected.map(|h| h.0), "ray {:?}", r);
+                if let (Some(a), Some(b)) = (got, expected) {
+                    assert!((a.1 - b.1).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn kdtree_keeps_planar_primitives(){
+        // Points on the faces of unit cells, where the SAH places its splits
+        let mut world = vec![];
+        for i in 0 .. 64 {
+            let (x, z) = ((i % 8) as f64, (i / 8) as f64);
+            world.push(mk_sphere(x + 0.5, 0.5, z + 0.5, 0.5, i));
+            world.push(mk_sphere(x + 1.0, 0.5, z + 0.5, 0.0, 100 + i));
+        }
+        let n = world.len();
+        let tree = KdTree::build(world);
+        for p in 0 .. n {
+            assert!(tree.prim_indices.contains(&p), "primitive {} is in no leaf", p);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::raymath::{Vec3, Ray3, constants, SamplingCfg, HitRay, HitRecord, Scene, MaterialId, dot, unit_vector, random_f64_normalized};
+use crate::spectral::{Spectrum, SampledWavelengths, N_SAMPLES};
+use crate::subsurface::sample_hg;
+
+// Integrator selection
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RaySort{
+    None,
+    Direction,
+    Origin
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct WavefrontCfg{
+    pub queue_size : usize,
+    pub sort : RaySort
+}
+
+impl WavefrontCfg{
+    pub fn new(queue_size:usize, sort:RaySort) -> WavefrontCfg{WavefrontCfg{queue_size, sort}}
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Integrator{
+    Path,
+    Wavefront(WavefrontCfg),
+    // Path tracing over sampled wavelengths
+    Spectral
+}
+
+// Path state
+// Shadow ray spawned while shading. Its contribution is added to the path
+// radiance if nothing is hit before t_max.
+#[derive(Debug, Copy, Clone)]
+pub struct ShadowRay{
+    pub ray : Ray3,
+    pub t_max : f64,
+    pub contribution : Spectrum
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PathState{
+    pub ray : Ray3,
+    pub throughput : Spectrum,
+    pub radiance : Spectrum,
+    // Sampled wavelengths of a spectral path, None for RGB
+    pub wavelengths : Option<SampledWavelengths>,
+    // Subsurface material the path is currently inside of
+    pub medium : Option<MaterialId>,
+    // Scattering events of the current random walk
+    pub walk : u32,
+    // Density of the extension ray's direction if lights were also sampled
+    // at its origin, for the MIS weight of emission it finds
+    pub scatter_pdf : Option<f64>,
+    pub depth : i32,
+    pub pixel : usize,
+    pub alive : bool
+}
+
+// Random walks longer than this are terminated
+const MAX_WALK : u32 = 1024;
+
+// MIS weight of a strategy with density pdf_a against one with pdf_b
+fn power_heuristic(pdf_a:f64, pdf_b:f64) -> f64{
+    let (a, b) = (pdf_a * pdf_a, pdf_b * pdf_b);
+    if a + b > 0.0 {a / (a + b)} else {0.0}
+}
+
+pub fn extension_cfg(scene:&Scene) -> SamplingCfg<'_>{SamplingCfg::new(0.001, constants::INFINITY_F64).with_masks(&scene.mats)}
+
+impl PathState{
+    pub fn new(r:Ray3, max_depth:i32, pixel:usize) -> PathState{
+        PathState{ray:r, throughput:Spectrum::ones(), radiance:Spectrum::zeros(), wavelengths:None, medium:None, walk:0, scatter_pdf:None, depth:max_depth, pixel, alive:max_depth > 0}
+    }
+
+    pub fn new_spectral(r:Ray3, max_depth:i32, pixel:usize) -> PathState{
+        let wavelengths = SampledWavelengths::sample_uniform(random_f64_normalized());
+        let mut path = PathState::new(r.with_lambda(wavelengths.hero()), max_depth, pixel);
+        path.wavelengths = Some(wavelengths);
+        path
+    }
+
+    // RGB reflectances and radiances in the path's representation
+    pub fn spectrum(&self, rgb:Vec3) -> Spectrum{
+        match &self.wavelengths {
+            Some(wavelengths) => wavelengths.uplift(rgb),
+            None => Spectrum::from_rgb(rgb)
+        }
+    }
+
+    // Linear RGB estimate of the path radiance
+    pub fn color(&self) -> Vec3{
+        match &self.wavelengths {
+            Some(wavelengths) => wavelengths.rgb(&self.radiance),
+            None => self.radiance.rgb()
+        }
+    }
+
+    // Number of channels in use in the path's spectra
+    fn channels(&self) -> usize{
+        if self.wavelengths.is_some() {N_SAMPLES} else {3}
+    }
+
+    // Step of a random walk through the medium the path is in, up to the
+    // surface hit by the extension ray. Returns true if the path scattered
+    // inside the medium (or was lost), false if it reached the surface.
+    fn walk_medium(&mut self, hit:Option<&HitRecord>, scene:&Scene, id:MaterialId) -> bool{
+        let medium = match scene.mats.materials[id].medium(&scene.mats) {
+            Some(medium) => medium,
+            None => {
+                self.medium = None;
+                return false;
+            }
+        };
+        let hit = match hit {
+            Some(hit) => hit,
+            None => {
+                // The medium is not closed
+                self.alive = false;
+                return true;
+            }
+        };
+        let sigma_t = self.spectrum(medium.sigma_t);
+        let n = self.channels();
+        let dir = unit_vector(self.ray.dir);
+        let dist = hit.t * self.ray.dir.length();
+        let transmittance = |d:f64| {
+            let mut tr = Spectrum::zeros();
+            for i in 0 .. n {
+                tr.c[i] = (-sigma_t.c[i] * d).exp();
+            }
+            tr
+        };
+        // Distance sampled from one channel picked at random; the pdf is
+        // the average over all channels.
+        let c = ((random_f64_normalized() * n as f64) as usize).min(n - 1);
+        let t = if sigma_t.c[c] > 0.0 {-(1.0 - random_f64_normalized()).ln() / sigma_t.c[c]} else {f64::MAX};
+        if t >= dist {
+            let tr = transmittance(dist);
+            let pdf = tr.c[.. n].iter().sum::<f64>() / n as f64;
+            self.throughput = self.throughput * tr * (1.0 / pdf);
+            return false;
+        }
+        let tr = transmittance(t);
+        let pdf = (0 .. n).map(|i| sigma_t.c[i] * tr.c[i]).sum::<f64>() / n as f64;
+        self.throughput = self.throughput * self.spectrum(medium.sigma_s) * tr * (1.0 / pdf);
+        self.ray = Ray3::new(self.ray.orig + dir * t, sample_hg(dir, medium.g)).with_lambda(self.ray.lambda);
+        self.scatter_pdf = None;
+        self.walk += 1;
+        if self.walk > MAX_WALK || pdf <= 0.0 {
+            self.alive = false;
+        }
+        true
+    }
+
+    // Shadow ray towards a point on a light picked uniformly
+    fn sample_light(&self, hit:&HitRecord, scene:&Scene) -> Option<ShadowRay>{
+        let n = scene.lights.len();
+        let light = &scene.lights[((random_f64_normalized() * n as f64) as usize).min(n - 1)];
+        let ls = light.sample(hit.p, &scene.mats)?;
+        let wo = unit_vector(self.ray.dir) * -1.0;
+        let mat = &scene.mats.materials[hit.mat];
+        let f = mat.eval(wo, ls.wi, self.ray.lambda, hit, &scene.mats);
+        if ls.pdf <= 0.0 || f.length2() == 0.0 || ls.radiance.length2() == 0.0 {
+            return None;
+        }
+        let light_pdf = ls.pdf / n as f64;
+        let weight = if light.is_delta() {1.0} else {power_heuristic(light_pdf, mat.pdf(wo, ls.wi, hit, &scene.mats))};
+        let contribution = self.spectrum(f.mul_elements(ls.radiance) * (weight / light_pdf)) * self.throughput;
+        Some(ShadowRay{ray:Ray3::new(hit.p, ls.wi).with_lambda(self.ray.lambda), t_max:ls.dist * (1.0 - 1e-4), contribution})
+    }
+
+    // Advance the path by one bounce given the result of its extension ray.
+    // Shadow rays to be tested by the caller are pushed to `shadows`.
+    pub fn shade(&mut self, hit:Option<HitRecord>, scene:&Scene, shadows:&mut Vec<ShadowRay>){
+        if let Some(id) = self.medium {
+            if self.walk_medium(hit.as_ref(), scene, id) {
+                return;
+            }
+        }
+        match hit {
+            Some(hit) => {
+                let mat = &scene.mats.materials[hit.mat];
+                let weight = match self.scatter_pdf {
+                    Some(pdf) => power_heuristic(pdf, scene.light_pdf(self.ray.orig, unit_vector(self.ray.dir), &hit)),
+                    None => 1.0
+                };
+                self.radiance = self.radiance + self.spectrum(mat.emitted(&hit, &scene.mats) * weight) * self.throughput;
+                let sample_lights = !scene.lights.is_empty() && mat.samples_lights(&scene.mats);
+                if sample_lights {
+                    shadows.extend(self.sample_light(&hit, scene));
+                }
+                match mat.sample(self.ray, hit, &scene.mats) {
+                    Some(scattered) => {
+                        self.scatter_pdf = if sample_lights {scattered.pdf} else {None};
+                        self.throughput = self.spectrum(scattered.attenuation) * self.throughput;
+                        self.ray = scattered.scattered;
+                        if mat.medium(&scene.mats).is_some() {
+                            let outward = if hit.front_face {hit.normal} else {hit.normal * -1.0};
+                            let inside = dot(self.ray.dir, outward) < 0.0;
+                            self.medium = if inside {Some(hit.mat)} else {None};
+                            self.walk = 0;
+                        }
+                        if let Some(wavelengths) = &mut self.wavelengths {
+                            if mat.is_dispersive(&scene.mats) {
+                                wavelengths.terminate_secondary();
+                            }
+                            self.ray.lambda = wavelengths.hero();
+                        }
+                    },
+                    None => {
+                        self.alive = false;
+                        return;
+                    }
+                }
+            },
+            None => {
+                let mut sky = scene.background.value(&self.ray);
+                let wi = unit_vector(self.ray.dir);
+                for light in scene.lights.iter() {
+                    let l = light.escaped(wi);
+                    if l.length2() > 0.0 {
+                        let weight = match self.scatter_pdf {
+                            Some(pdf) => power_heuristic(pdf, light.pdf(self.ray.orig, wi) / scene.lights.len() as f64),
+                            None => 1.0
+                        };
+                        sky = sky + l * weight;
+                    }
+                }
+                self.radiance = self.radiance + self.spectrum(sky) * self.throughput;
+                self.alive = false;
+                return;
+            }
+        }
+        self.depth -= 1;
+        if self.depth <= 0 {
+            self.alive = false;
+        }
+    }
+}
+
+pub fn occluded(scene:&Scene, shadow:&ShadowRay) -> bool{
+    scene.world.hit(&shadow.ray, SamplingCfg::new(0.001, shadow.t_max).with_masks(&scene.mats)).is_some()
+}
+
+pub fn ray_color(r : Ray3, scene:&Scene, depth:i32) -> Vec3 {
+    trace_path(PathState::new(r, depth, 0), scene)
+}
+
+pub fn ray_color_spectral(r : Ray3, scene:&Scene, depth:i32) -> Vec3 {
+    trace_path(PathState::new_spectral(r, depth, 0), scene)
+}
+
+fn trace_path(mut path:PathState, scene:&Scene) -> Vec3 {
+    let mut shadows = vec![];
+    while path.alive {
+        let rec = scene.world.hit(&path.ray, extension_cfg(scene));
+        path.shade(rec, scene, &mut shadows);
+        for shadow in shadows.drain(..) {
+            if !occluded(scene, &shadow) {
+                path.radiance = path.radiance + shadow.contribution;
+            }
+        }
+    }
+    path.color()
+}
@@ -0,0 +1,71 @@
+use crate::raymath::{Vec3, vec3, dot, unit_vector, Onb, Ray3, HitRecord, ScatterResult, MaterialId, MaterialCollection, random_f64_normalized};
+use crate::microfacet::{Ggx, reflect_about, fresnel_dielectric};
+use crate::texture::Texture;
+
+// Material combinators
+// Both reference materials added earlier to the same MaterialCollection.
+// They are only built by MaterialCollection::add_mix and add_coated, which
+// check this, so a combinator can not end up referencing itself.
+
+// Stochastic blend: b is used with probability weight, a otherwise
+#[derive(Debug)]
+pub struct Mix{
+    pub(crate) a : MaterialId,
+    pub(crate) b : MaterialId,
+    // Read from the first channel
+    weight : Texture
+}
+
+impl Mix{
+    pub(crate) fn new(a:MaterialId, b:MaterialId, weight:Texture) -> Mix{Mix{a, b, weight}}
+
+    pub fn weight(&self, rec:&HitRecord) -> f64{
+        self.weight.value(rec.u, rec.v, rec.p).x.clamp(0.0, 1.0)
+    }
+
+    pub fn pick(&self, rec:&HitRecord) -> MaterialId{
+        if random_f64_normalized() < self.weight(rec) {self.b} else {self.a}
+    }
+}
+
+// Dielectric layer over any base material. Light reflects off the coat with
+// its Fresnel reflectance, the rest reaches the base and is attenuated again by
+// the coat's transmittance on the way out. Refraction inside the thin coat and
+// interreflections between coat and base are ignored.
+#[derive(Debug)]
+pub struct Coated{
+    pub(crate) base : MaterialId,
+    ior : f64,
+    distrib : Ggx
+}
+
+impl Coated{
+    pub(crate) fn new(base:MaterialId, ior:f64, roughness:f64) -> Coated{
+        Coated{base, ior, distrib:Ggx::from_roughness(roughness)}
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord, mats:&MaterialCollection) -> Option<ScatterResult>{
+        let base = &mats.materials[self.base];
+        let frame = Onb::from_w(rec.normal);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        if wo.z <= 0.0 || !rec.front_face {
+            return base.scatter(r_in, rec, mats);
+        }
+        let smooth = self.distrib.is_smooth();
+        let wm = if smooth {vec3(0.0, 0.0, 1.0)} else {self.distrib.sample_wm_random(wo)};
+        if random_f64_normalized() < fresnel_dielectric(dot(wo, wm), self.ior) {
+            let wi = reflect_about(wo, wm);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            let weight = if smooth {1.0} else {self.distrib.g(wo, wi) / self.distrib.g1(wo)};
+            return Some(ScatterResult{attenuation:Vec3::ones() * weight, scattered:Ray3::new(rec.p, frame.to_world(wi))});
+        }
+        let mut res = base.scatter(r_in, rec, mats)?;
+        let cos_out = dot(unit_vector(res.scattered.dir), rec.normal);
+        if cos_out > 0.0 {
+            res.attenuation = res.attenuation * (1.0 - fresnel_dielectric(cos_out, self.ior));
+        }
+        Some(res)
+    }
+}
@@ -0,0 +1,20 @@
+#![allow(dead_code)]
+
+// Renderer core, also usable from other crates, for example to add materials
+// through raymath::Bsdf. The binary adds the demo scenes and the band renderer.
+pub mod raymath;
+pub mod integrator;
+pub mod accel;
+pub mod texture;
+pub mod noise;
+pub mod microfacet;
+pub mod principled;
+pub mod spectral;
+pub mod thinfilm;
+pub mod normalmap;
+pub mod layered;
+pub mod subsurface;
+pub mod merl;
+pub mod volume;
+pub mod mtl;
+pub mod light;
@@ -0,0 +1,225 @@
+use crate::raymath::{Vec3, vec3, Ray3, Onb, Sphere, HitRay, HitRecord, HittableObject, MaterialCollection, SamplingCfg, dot, unit_vector, random_f64_normalized, constants};
+
+// Lights sampled explicitly by the integrator (next event estimation)
+// Emissive spheres are found in the world when the Scene is built. Light
+// samples and emission the extension rays find on lights are combined with
+// multiple importance sampling. Point, spot and sun lights have no geometry
+// and are added with Scene::with_light; only a sun with a nonzero angular
+// diameter can also be found by rays leaving the scene.
+
+// Direction from a shading point towards a point on a light
+#[derive(Debug, Copy, Clone)]
+pub struct LightSample{
+    pub wi : Vec3,
+    // Distance to the sampled point along wi
+    pub dist : f64,
+    pub radiance : Vec3,
+    // Solid angle density of wi. Delta lights report 1 with the irradiance
+    // arriving at the point as radiance.
+    pub pdf : f64
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Light{
+    // Emissive sphere, sampled uniformly over the cone it subtends
+    Sphere(Sphere),
+    // Intensity in radiance times area
+    Point{position:Vec3, intensity:Vec3},
+    // Point light limited to a cone around direction, fading out between
+    // the cosines of the inner and outer half angles
+    Spot{position:Vec3, direction:Vec3, intensity:Vec3, cos_inner:f64, cos_outer:f64},
+    // Light from the unit direction towards the sun, with the irradiance it
+    // gives a surface facing it. cos_max is the cosine of the sun's angular
+    // radius, 1 for a delta light.
+    Sun{direction:Vec3, irradiance:Vec3, cos_max:f64}
+}
+
+fn smoothstep(a:f64, b:f64, x:f64) -> f64{
+    if a >= b {
+        return if x >= b {1.0} else {0.0};
+    }
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn cone_pdf(cos_max:f64) -> f64{1.0 / (2.0 * constants::PI_F64 * (1.0 - cos_max))}
+
+// Uniform direction in the cone of cos_max around frame.w
+fn sample_cone(frame:&Onb, cos_max:f64) -> Vec3{
+    let cos_theta = 1.0 - random_f64_normalized() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * constants::PI_F64 * random_f64_normalized();
+    frame.to_world(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+}
+
+// Frame around the direction to the sphere and the cosine of the cone's
+// half angle, None from inside the sphere. A negative radius only turns the
+// sphere's normals inwards, so the cone is that of the absolute radius.
+fn sphere_cone(sphere:&Sphere, p:Vec3) -> Option<(Onb, f64)>{
+    let to_center = sphere.center - p;
+    let d2 = to_center.length2();
+    let r2 = sphere.radius * sphere.radius;
+    if d2 <= r2 {
+        return None;
+    }
+    Some((Onb::from_w(unit_vector(to_center)), (1.0 - r2 / d2).sqrt()))
+}
+
+// Unit direction and distance from p to a point light, None when p is on it
+fn towards(position:Vec3, p:Vec3) -> Option<(Vec3, f64)>{
+    let d = position - p;
+    let dist2 = d.length2();
+    if dist2 <= 1e-12 {
+        return None;
+    }
+    let dist = dist2.sqrt();
+    Some((d / dist, dist))
+}
+
+impl Light{
+    pub fn point(position:Vec3, intensity:Vec3) -> Light{Light::Point{position, intensity}}
+    // Half angles of the cone in degrees, full intensity inside inner_angle
+    pub fn spot(position:Vec3, direction:Vec3, intensity:Vec3, outer_angle:f64, inner_angle:f64) -> Light{
+        let cos_outer = outer_angle.to_radians().cos();
+        let cos_inner = inner_angle.min(outer_angle).to_radians().cos();
+        Light::Spot{position, direction:unit_vector(direction), intensity, cos_inner, cos_outer}
+    }
+    // Sun shining along `direction` (pointing away from the sun), with an
+    // angular diameter in degrees. The real sun is about 0.53.
+    pub fn sun(direction:Vec3, irradiance:Vec3, angular_diameter:f64) -> Light{
+        let cos_max = (0.5 * angular_diameter.to_radians()).cos();
+        Light::Sun{direction:unit_vector(direction) * -1.0, irradiance, cos_max}
+    }
+
+    // Lights that no ray can hit, sampled with probability one
+    pub fn is_delta(&self) -> bool{
+        match self {
+            Light::Sphere(_) => false,
+            Light::Point{..} | Light::Spot{..} => true,
+            Light::Sun{cos_max, ..} => *cos_max >= 1.0
+        }
+    }
+
+    // True if the hit lies on this light
+    pub fn contains(&self, rec:&HitRecord) -> bool{
+        match self {
+            Light::Sphere(sphere) => {
+                let radius = sphere.radius.abs();
+                rec.mat == sphere.material && ((rec.p - sphere.center).length() - radius).abs() <= 1e-6 * radius.max(1.0)
+            },
+            _ => false
+        }
+    }
+
+    // Radiance arriving along a ray that left the scene in the unit direction wi
+    pub fn escaped(&self, wi:Vec3) -> Vec3{
+        match self {
+            Light::Sun{direction, irradiance, cos_max} if *cos_max < 1.0 && dot(wi, *direction) >= *cos_max => {
+                *irradiance * cone_pdf(*cos_max)
+            },
+            _ => Vec3::zeros()
+        }
+    }
+
+    pub fn sample(&self, p:Vec3, mats:&MaterialCollection) -> Option<LightSample>{
+        match self {
+            Light::Sphere(sphere) => {
+                let (frame, cos_max) = sphere_cone(sphere, p)?;
+                let wi = sample_cone(&frame, cos_max);
+                let rec = sphere.hit(&Ray3::new(p, wi), SamplingCfg::new(0.0, constants::INFINITY_F64))?;
+                let radiance = mats.materials[sphere.material].emitted(&rec, mats);
+                Some(LightSample{wi, dist:rec.t, radiance, pdf:cone_pdf(cos_max)})
+            },
+            Light::Point{position, intensity} => {
+                let (wi, dist) = towards(*position, p)?;
+                Some(LightSample{wi, dist, radiance:*intensity / (dist * dist), pdf:1.0})
+            },
+            Light::Spot{position, direction, intensity, cos_inner, cos_outer} => {
+                let (wi, dist) = towards(*position, p)?;
+                let falloff = smoothstep(*cos_outer, *cos_inner, dot(wi * -1.0, *direction));
+                Some(LightSample{wi, dist, radiance:*intensity * (falloff / (dist * dist)), pdf:1.0})
+            },
+            Light::Sun{direction, irradiance, cos_max} => {
+                if *cos_max >= 1.0 {
+                    return Some(LightSample{wi:*direction, dist:constants::INFINITY_F64, radiance:*irradiance, pdf:1.0});
+                }
+                let wi = sample_cone(&Onb::from_w(*direction), *cos_max);
+                Some(LightSample{wi, dist:constants::INFINITY_F64, radiance:*irradiance * cone_pdf(*cos_max), pdf:cone_pdf(*cos_max)})
+            }
+        }
+    }
+
+    // Density with which sample() picks the unit direction wi from p
+    pub fn pdf(&self, p:Vec3, wi:Vec3) -> f64{
+        match self {
+            Light::Sphere(sphere) => match sphere_cone(sphere, p) {
+                Some((_, cos_max)) if sphere.hit(&Ray3::new(p, wi), SamplingCfg::new(0.0, constants::INFINITY_F64)).is_some() => {
+                    cone_pdf(cos_max)
+                },
+                _ => 0.0
+            },
+            Light::Sun{direction, cos_max, ..} if *cos_max < 1.0 && dot(wi, *direction) >= *cos_max => cone_pdf(*cos_max),
+            _ => 0.0
+        }
+    }
+}
+
+// Spheres whose material emits. Cut out emitters are left to the extension
+// rays since a sampled point may fall into a hole.
+pub fn collect_lights(obj:&HittableObject, mats:&MaterialCollection, lights:&mut Vec<Light>){
+    match obj {
+        HittableObject::Sphere(sphere) if mats.materials[sphere.material].is_light(mats) => lights.push(Light::Sphere(*sphere)),
+        _ => {
+            for child in obj.children() {
+                collect_lights(child, mats, lights);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::raymath::{Material, HitRay};
+
+    // An emissive sphere of the given radius at the origin, with its light
+    fn sphere_light(radius:f64) -> (MaterialCollection, Sphere, Light){
+        let mut mats = MaterialCollection::new();
+        let lamp = mats.add(Material::mk_diffuse_light(vec3(4.0, 4.0, 4.0)));
+        let sphere = Sphere::new(Vec3::zeros(), radius, lamp);
+        let mut lights = vec![];
+        collect_lights(&HittableObject::Sphere(sphere), &mats, &mut lights);
+        assert_eq!(lights.len(), 1);
+        (mats, sphere, lights[0])
+    }
+
+    #[test]
+    fn negative_radius_spheres_are_sampled_like_positive_ones(){
+        let p = vec3(0.0, 0.0, 4.0);
+        let (_, _, outward) = sphere_light(1.0);
+        let (mats, sphere, inward) = sphere_light(-1.0);
+        for _ in 0 .. 100 {
+            let ls = inward.sample(p, &mats).unwrap();
+            assert!((ls.pdf - outward.pdf(p, ls.wi)).abs() < 1e-9);
+            assert!((inward.pdf(p, ls.wi) - ls.pdf).abs() < 1e-9);
+            let rec = sphere.hit(&Ray3::new(p, ls.wi), SamplingCfg::new(1e-3, constants::INFINITY_F64)).unwrap();
+            assert!(inward.contains(&rec));
+            assert!((ls.dist - rec.t).abs() < 1e-9);
+        }
+        // Directions that miss the sphere
+        assert_eq!(inward.pdf(p, vec3(1.0, 0.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn point_lights_are_not_sampled_from_their_position(){
+        let mats = MaterialCollection::new();
+        let position = vec3(1.0, 2.0, 3.0);
+        let point = Light::point(position, vec3(8.0, 8.0, 8.0));
+        let spot = Light::spot(position, vec3(0.0, -1.0, 0.0), vec3(8.0, 8.0, 8.0), 30.0, 20.0);
+        for light in [point, spot] {
+            assert!(light.sample(position, &mats).is_none());
+            let ls = light.sample(position - vec3(0.0, 2.0, 0.0), &mats).unwrap();
+            assert_eq!((ls.wi, ls.dist, ls.radiance), (vec3(0.0, 1.0, 0.0), 2.0, vec3(2.0, 2.0, 2.0)));
+        }
+    }
+}
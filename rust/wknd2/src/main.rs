@@ -1,12 +1,16 @@
 
 #![allow(dead_code)]
 
-mod raymath;
+use wknd::{raymath, integrator, texture, noise, microfacet, principled, spectral, thinfilm, normalmap, merl, volume};
+mod wavefront;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use raymath::{Vec3, Ray3, vec3, lerp3, unit_vector, 
+use raymath::{Vec3, vec3, unit_vector, 
     hit_sphere, write_color_stdout, 
-    HitRecord, HittableObject, constants, SamplingCfg, Sphere, HitRay, MaterialCollection, mk_sphere, random_f64, mk_sphere2};
+    HitRecord, HittableObject, AccelKind, Scene, Background, Sphere, MaterialCollection, mk_sphere, random_f64, mk_sphere2};
+use integrator::{Integrator, ray_color, ray_color_spectral};
+use spectral::IorCurve;
+use thinfilm::ThinFilm;
 
 use std::{fs::File, f64::consts::PI};
 use std::io::{Write, Stdout};
@@ -20,59 +24,34 @@ extern crate pbr;
 use pbr::ProgressBar;
 
 use crate::raymath::vec3g;
-
-fn ray_color(mut r : Ray3, world:&dyn HitRay, mats:&MaterialCollection, mut depth:i32) -> Vec3 {
-    let mut col = Vec3::zeros();
-    let mut transmissibility = Vec3::ones();
-    while depth > 0 {
-        let cfg= SamplingCfg::new(0.001, constants::INFINITY_F64);
-        let rec = world.hit(&r, cfg);
-        match rec {
-            Some(hit) => {
-                //let target = hit.p + hit.normal + Vec3::random_unit_vector();
-                let scatteredResult = mats.materials[hit.mat].scatter(r, hit);
-                match scatteredResult {
-                    Some(scattered)=>{
-                        transmissibility = scattered.attenuation.mul_elements(transmissibility);
-                        r = scattered.scattered;
-                    },
-                    None=>{
-                        transmissibility = Vec3::zeros();
-                        break;
-                    }
-                }
-
-                //let target = hit.p + hit.normal + Vec3::random_in_hemisphere(hit.normal);
-                //r = Ray3::new(hit.p, target - hit.p);
-                //let v = (hit.normal + vec3(1.0, 1.0, 1.0)) * 0.5;
-                //transmissibility = transmissibility * 0.5;
-            },
-            None =>{
-                let udir = unit_vector(r.direction());
-                let t = 0.5 * (udir.y + 1.0);
-                let v = lerp3(vec3(1.0,1.0,1.0), vec3(0.5, 0.7, 1.0), t);
-                col = v.mul_elements(transmissibility);
-                break;
-            }
-        }
-        depth = depth - 1;
-    }
-    col
-}
+use crate::texture::{Texture, ImageTexture, WrapMode};
+use crate::normalmap::NormalMap;
+use crate::noise::NoisePattern;
+use crate::microfacet::{metals, Conductor};
+use crate::merl::MerlBrdf;
+use crate::volume::DensityGrid;
+use crate::noise::Perlin;
+use raymath::{Aabb, mk_volume};
+use raymath::{Bsdf, Onb, Ray3, ScatterResult, dot};
+use wknd::mtl::{load_mtl, MtlLibrary};
+use wknd::light::Light;
+use crate::principled::Principled;
 
 struct Cfg{
     pub aspect_ratio : f64,
     pub image_width:i32,
     pub image_height:i32,
     pub samples_per_pixel:i32,
-    pub max_depth:i32
+    pub max_depth:i32,
+    pub integrator:Integrator
 }
 
 //fn render_line(pixels:&mut Vec<i32>, cfg: &Cfg, cam:&Camera, hittable:&HittableObject, mats:&MaterialCollection, y:i32, ){
-fn render_line(pixels:&mut [i32], cfg: &Cfg, cam:&Camera, hittable:&HittableObject, mats:&MaterialCollection, y:i32, ){
+fn render_line(pixels:&mut [i32], cfg: &Cfg, cam:&Camera, scene:&Scene, y:i32, ){
         let fj = (cfg.image_height - y -1) as f64;
         let f_w = (cfg.image_width - 1) as f64;
         let f_h = (cfg.image_height -1) as f64;
+        let trace = if let Integrator::Spectral = cfg.integrator {ray_color_spectral} else {ray_color};
         for i in 0 .. cfg.image_width
         {
             let fi = i as f64;
@@ -82,7 +61,7 @@ fn render_line(pixels:&mut [i32], cfg: &Cfg, cam:&Camera, hittable:&HittableObje
                 let v = (fj + random_f64_normalized()) / f_h;
                 let r = cam.get_ray(u, v);
                 //let r = Ray3::new(origin, lower_left_corner + (horizontal * u) + (vertical * v));
-                pixel_color = pixel_color + ray_color(r, hittable, &mats, cfg.max_depth);
+                pixel_color = pixel_color + trace(r, scene, cfg.max_depth);
             }
             //let idx = ((cfg.image_height - y - 1) * cfg.image_width + i) as usize;
             let idx = (i) as usize;
@@ -90,7 +69,7 @@ fn render_line(pixels:&mut [i32], cfg: &Cfg, cam:&Camera, hittable:&HittableObje
         }
 }
 
-fn build_world_1() -> (HittableObject, MaterialCollection) {
+fn build_world_1(accel:AccelKind) -> Scene {
     // World
     let mut mats = MaterialCollection::new();
     let material_ground = mats.add(Material::mk_lambert(vec3(0.8, 0.8, 0.0))); // 0
@@ -100,6 +79,7 @@ fn build_world_1() -> (HittableObject, MaterialCollection) {
     //let material_center = mats.add(Material::mk_dielectric(1.5)); // 1
     //let material_left = mats.add(Material::mk_metal(vec3(0.8, 0.8, 0.8), 0.3)); // 2
     let material_left = mats.add(Material::mk_dielectric(1.5)); // 2
+    //let material_left = mats.add(Material::mk_dielectric_absorbing(1.5, vec3(0.4, 0.8, 0.5), 0.5)); // 2
     //let material_right = mats.add(Material::mk_metal(vec3(0.8, 0.6, 0.2),1.0)); // 3
     let material_right = mats.add(Material::mk_metal(vec3(0.8, 0.6, 0.2),0.0)); // 3
 
@@ -110,10 +90,10 @@ fn build_world_1() -> (HittableObject, MaterialCollection) {
     world.push(HittableObject::Sphere(Sphere{center:vec3(-1.0,0.0,-1.0), radius:-0.4, material:material_left}));
     world.push(HittableObject::Sphere(Sphere{center:vec3(1.0,0.0,-1.0), radius:0.5, material:material_right}));
 
-    (HittableObject::wrap(world),mats)
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
 }
 
-fn build_world_2() -> (HittableObject, MaterialCollection) {
+fn build_world_2(accel:AccelKind) -> Scene {
     // World
     let R = f64::cos(PI / 4.0);
 
@@ -125,10 +105,10 @@ fn build_world_2() -> (HittableObject, MaterialCollection) {
     world.push(HittableObject::Sphere(Sphere{center:vec3(-R,0.0,-1.0), radius:R, material:material_left}));
     world.push(HittableObject::Sphere(Sphere{center:vec3(R,0.0,-1.0), radius:R, material:material_right}));
 
-    (HittableObject::wrap(world),mats)
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
 }
 
-fn build_world_3() -> (HittableObject, MaterialCollection) {
+fn build_world_3(accel:AccelKind) -> Scene {
     // World
     let R = f64::cos(PI / 4.0);
     let mut world = HittableObject::mk_list();
@@ -169,7 +149,520 @@ fn build_world_3() -> (HittableObject, MaterialCollection) {
     let mat3 = mats.add_metal(vec3(0.7, 0.6, 0.5), 0.0);
     world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, mat3));
 
-    (HittableObject::wrap(world),mats)
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+fn build_world_4(accel:AccelKind) -> Scene {
+    // Textured spheres
+    let mut world = HittableObject::mk_list();
+    let mut mats = MaterialCollection::new();
+
+    let checker = Texture::checker(0.5, vec3(0.2, 0.3, 0.1), vec3(0.9, 0.9, 0.9));
+    let ground_material = mats.add_lambert_tex(checker);
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let fine_checker = Texture::checker(0.1, vec3(0.8, 0.1, 0.1), vec3(0.9, 0.9, 0.9));
+    let mat1 = mats.add_lambert_tex(fine_checker);
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, mat1));
+    let brushed = Texture::checker(0.25, vec3(0.7, 0.6, 0.5), vec3(0.9, 0.9, 0.9));
+    let mat2 = mats.add_metal_tex(brushed, 0.1);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, mat2));
+    let earth = match Texture::load_image("earthmap.jpg", WrapMode::Repeat) {
+        Ok(tex) => tex,
+        Err(e) => {
+            println!("earthmap.jpg: {}", e);
+            Texture::constant(vec3(0.0, 1.0, 1.0))
+        }
+    };
+    let mat3 = mats.add_lambert_tex(earth);
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, mat3));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+fn build_world_5(accel:AccelKind) -> Scene {
+    // Procedural textures
+    let mut world = HittableObject::mk_list();
+    let mut mats = MaterialCollection::new();
+
+    let marble = Texture::noise(1, NoisePattern::Marble, 4.0, vec3(0.1, 0.1, 0.12), vec3(0.9, 0.9, 0.85));
+    let ground_material = mats.add_lambert_tex(marble);
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let wood = Texture::noise(2, NoisePattern::Wood, 1.5, vec3(0.45, 0.25, 0.1), vec3(0.75, 0.5, 0.25));
+    let mat1 = mats.add_lambert_tex(wood);
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, mat1));
+    let cloud = Texture::noise(3, NoisePattern::Cloud, 2.0, vec3(0.3, 0.5, 0.9), vec3(1.0, 1.0, 1.0));
+    let mat2 = mats.add_lambert_tex(cloud);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, mat2));
+    let turb = Texture::noise(4, NoisePattern::Turbulence, 3.0, vec3(0.1, 0.1, 0.1), vec3(0.9, 0.7, 0.5));
+    let mat3 = mats.add_metal_tex(turb, 0.2);
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, mat3));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+fn build_world_6(accel:AccelKind) -> Scene {
+    // Cornell box lit only by its lamp. Walls are huge spheres, as in smallpt,
+    // with the open front looking out onto the black background.
+    let mut world = HittableObject::mk_list();
+    let mut mats = MaterialCollection::new();
+
+    let red = mats.add_lambert(vec3(0.75, 0.25, 0.25));
+    let blue = mats.add_lambert(vec3(0.25, 0.25, 0.75));
+    let white = mats.add_lambert(vec3(0.75, 0.75, 0.75));
+    let mirror = mats.add_metal(vec3(0.999, 0.999, 0.999), 0.0);
+    let glass = mats.add_dielectric(1.5);
+    let lamp = mats.add_diffuse_light(vec3(12.0, 12.0, 12.0));
+
+    let r = 1e5;
+    world.push(mk_sphere(r + 1.0, 40.8, 81.6, r, red)); // left
+    world.push(mk_sphere(-r + 99.0, 40.8, 81.6, r, blue)); // right
+    world.push(mk_sphere(50.0, 40.8, r, r, white)); // back
+    world.push(mk_sphere(50.0, r, 81.6, r, white)); // floor
+    world.push(mk_sphere(50.0, -r + 81.6, 81.6, r, white)); // ceiling
+    world.push(mk_sphere(27.0, 16.5, 47.0, 16.5, mirror));
+    world.push(mk_sphere(73.0, 16.5, 78.0, 16.5, glass));
+    world.push(mk_sphere(50.0, 681.6 - 0.27, 81.6, 600.0, lamp));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats).with_background(Background::Solid(Vec3::zeros()))
+}
+
+fn build_world_7(accel:AccelKind) -> Scene {
+    // Microfacet materials. Roughness increases along z.
+    let mut world = HittableObject::mk_list();
+    let mut mats = MaterialCollection::new();
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let (gold_eta, gold_k) = metals::gold();
+    let (copper_eta, copper_k) = metals::copper();
+    for i in 0 .. 4 {
+        let roughness = i as f64 / 3.0;
+        let z = -3.0 + 2.0 * i as f64;
+        let gold = mats.add_conductor(gold_eta, gold_k, roughness);
+        world.push(mk_sphere(-1.5, 0.7, z, 0.7, gold));
+        let copper = mats.add_conductor(copper_eta, copper_k, roughness);
+        world.push(mk_sphere(0.0, 0.7, z, 0.7, copper));
+        let glass = mats.add_rough_dielectric(1.5, roughness);
+        world.push(mk_sphere(1.5, 0.7, z, 0.7, glass));
+    }
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+fn build_world_8(accel:AccelKind) -> Scene {
+    // Principled material presets
+    let mut world = HittableObject::mk_list();
+    let mut mats = MaterialCollection::new();
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let plastic = Principled{roughness:0.3, ..Principled::new(Texture::constant(vec3(0.1, 0.3, 0.8)))};
+    let brushed_gold = Principled{metallic:1.0, roughness:0.35, ..Principled::new(Texture::constant(vec3(1.0, 0.77, 0.34)))};
+    let car_paint = Principled{metallic:0.6, roughness:0.4, clearcoat:1.0, ..Principled::new(Texture::constant(vec3(0.6, 0.05, 0.05)))};
+    let frosted_glass = Principled{transmission:1.0, roughness:0.2, ..Principled::new(Texture::constant(vec3(0.95, 0.95, 0.95)))};
+    let velvet = Principled{roughness:0.9, sheen:1.0, specular:0.1, ..Principled::new(Texture::constant(vec3(0.3, 0.05, 0.3)))};
+    let skin = Principled{roughness:0.5, subsurface:1.0, ..Principled::new(Texture::constant(vec3(0.9, 0.6, 0.5)))};
+    for (i, p) in [plastic, brushed_gold, car_paint, frosted_glass, velvet, skin].into_iter().enumerate() {
+        let mat = mats.add_principled(p);
+        world.push(mk_sphere(0.0, 0.6, -3.75 + 1.5 * i as f64, 0.6, mat));
+    }
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Dispersive glass, best viewed with Integrator::Spectral
+fn build_world_9(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert(vec3(0.8, 0.8, 0.8));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let lamp = mats.add_diffuse_light(vec3(8.0, 8.0, 8.0));
+    world.push(mk_sphere(-4.0, 7.0, 0.0, 2.0, lamp));
+
+    let bk7 = mats.add_dielectric_dispersive(IorCurve::bk7());
+    let sf11 = mats.add_dielectric_dispersive(IorCurve::sf11());
+    let diamond = mats.add_dielectric_dispersive(IorCurve::diamond());
+    let cauchy = mats.add_dielectric_dispersive(IorCurve::Cauchy{a:1.5, b:0.02});
+    world.push(mk_sphere(0.0, 1.0, -3.0, 1.0, bk7));
+    world.push(mk_sphere(0.0, 1.0, -1.0, 1.0, sf11));
+    world.push(mk_sphere(0.0, 1.0, 1.0, 1.0, diamond));
+    world.push(mk_sphere(0.0, 1.0, 3.0, 1.0, cauchy));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats).with_background(Background::Solid(vec3(0.02, 0.02, 0.03)))
+}
+
+// Thin-film coatings: soap bubbles, an oil slick and anodized titanium
+fn build_world_10(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let oil = ThinFilm::with_textures(Texture::noise(4, NoisePattern::Turbulence, 0.5, vec3(200.0, 200.0, 200.0), vec3(900.0, 900.0, 900.0)), Texture::constant(vec3(1.47, 1.47, 1.47)));
+    // Oil on wet asphalt
+    let ground_material = mats.add_metal_film(vec3(0.04, 0.04, 0.04), 0.0, oil);
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let swirl = Texture::noise(5, NoisePattern::Marble, 2.0, vec3(250.0, 250.0, 250.0), vec3(700.0, 700.0, 700.0));
+    let bubble = mats.add_dielectric_film(1.0, ThinFilm::with_textures(swirl, Texture::constant(vec3(1.33, 1.33, 1.33))));
+    world.push(mk_sphere(0.0, 1.2, -2.5, 1.2, bubble));
+    world.push(mk_sphere(1.5, 0.6, -0.6, 0.6, bubble));
+
+    // Titanium under oxide layers of increasing thickness
+    for (i, thickness) in [60.0, 120.0, 180.0, 240.0].into_iter().enumerate() {
+        let anodized = mats.add_metal_film(vec3(0.54, 0.5, 0.45), 0.05, ThinFilm::new(thickness, 2.4));
+        world.push(mk_sphere(-1.0, 0.5, 0.5 + 1.1 * i as f64, 0.5, anodized));
+    }
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Normal and bump mapped spheres
+fn build_world_11(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    // Stucco: bump mapped diffuse
+    let stucco = Texture::noise(6, NoisePattern::Turbulence, 8.0, Vec3::zeros(), Vec3::ones());
+    let mat1 = mats.add(Material::mk_lambert(vec3(0.8, 0.5, 0.3)).with_normal_map(NormalMap::bump(stucco, 0.02)));
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, mat1));
+
+    // Ribbed metal: tangent space normal map of ridges along u, encoded as a linear image
+    let (w, h) = (256, 4);
+    let ridges = (0 .. w * h).map(|i| {
+        let slope = 0.6 * (2.0 * PI * 24.0 * (i % w) as f64 / w as f64).cos();
+        let n = unit_vector(vec3(-slope, 0.0, 1.0));
+        n * 0.5 + Vec3::ones() * 0.5
+    }).collect();
+    let ridge_map = Texture::image(Arc::new(ImageTexture::from_texels(w, h, WrapMode::Repeat, ridges)));
+    let mat2 = mats.add(Material::mk_metal(vec3(0.8, 0.8, 0.85), 0.05).with_normal_map(NormalMap::tangent(ridge_map, 1.0)));
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, mat2));
+
+    // Hammered glass
+    let dents = Texture::noise(7, NoisePattern::Fbm, 4.0, Vec3::zeros(), Vec3::ones());
+    let mat3 = mats.add(Material::mk_dielectric(1.5).with_normal_map(NormalMap::bump(dents, 0.05)));
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, mat3));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Alpha cutouts: a lattice cage, a perforated shell and a half transparent ghost
+fn build_world_12(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.3, 0.1), vec3(0.9, 0.9, 0.9)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let (w, h) = (64, 32);
+    let bars = (0 .. w * h).map(|i| if (i % w) % 8 == 0 || (i / w) % 8 == 0 {Vec3::ones()} else {Vec3::zeros()}).collect();
+    let lattice = Texture::image(Arc::new(ImageTexture::from_texels(w, h, WrapMode::Repeat, bars)));
+    let cage = mats.add(Material::mk_metal(vec3(0.7, 0.6, 0.5), 0.2).with_alpha(lattice));
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, cage));
+    let caged = mats.add_lambert(vec3(0.8, 0.1, 0.1));
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 0.5, caged));
+
+    let holes = Texture::checker(0.25, Vec3::zeros(), Vec3::ones());
+    let shell = mats.add(Material::mk_lambert(vec3(0.1, 0.4, 0.8)).with_alpha(holes));
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, shell));
+
+    let ghost = mats.add(Material::mk_lambert(vec3(0.9, 0.9, 0.9)).with_alpha(Texture::constant(vec3(0.4, 0.4, 0.4))));
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, ghost));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Layered and mixed materials
+fn build_world_13(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    // Car paint: glossy clearcoat over a rough metallic base
+    let flake = mats.add_metal(vec3(0.6, 0.05, 0.05), 0.4);
+    let car_paint = mats.add_coated(flake, 1.5, 0.0).unwrap();
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, car_paint));
+
+    // Varnished wood
+    let wood = mats.add_lambert_tex(Texture::noise(2, NoisePattern::Wood, 1.5, vec3(0.45, 0.25, 0.1), vec3(0.75, 0.5, 0.25)));
+    let varnish = mats.add_coated(wood, 1.5, 0.15).unwrap();
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, varnish));
+
+    // Brushed steel under patches of dirt
+    let steel = mats.add_metal(vec3(0.7, 0.7, 0.75), 0.1);
+    let dirt = mats.add_lambert(vec3(0.25, 0.18, 0.1));
+    let patches = Texture::noise(8, NoisePattern::Turbulence, 3.0, Vec3::zeros(), vec3(1.5, 1.5, 1.5));
+    let dirty_steel = mats.add_mix(steel, dirt, patches).unwrap();
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, dirty_steel));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Random walk subsurface scattering: skin, wax, marble and milk
+fn build_world_14(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let skin = mats.add_subsurface(1.4, vec3(0.85, 0.6, 0.5), vec3(0.4, 0.15, 0.08), 0.0);
+    let wax = mats.add_subsurface(1.45, vec3(0.9, 0.75, 0.4), vec3(0.6, 0.35, 0.15), 0.0);
+    let marble = mats.add_subsurface(1.5, vec3(0.9, 0.9, 0.88), vec3(0.15, 0.15, 0.15), 0.0);
+    let milk = mats.add_subsurface(1.35, vec3(0.95, 0.95, 0.9), vec3(0.3, 0.3, 0.2), 0.7);
+    for (i, mat) in [skin, wax, marble, milk].into_iter().enumerate() {
+        world.push(mk_sphere(0.0, 0.6, -3.0 + 2.0 * i as f64, 0.6, mat));
+    }
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Anisotropic brushed metals
+fn build_world_15(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let (eta, k) = metals::aluminium();
+    // Brushed along the tangent and across it
+    let along = mats.add_anisotropic_conductor(eta, k, 0.05, 0.5, 0.0);
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, along));
+    let across = mats.add_anisotropic_conductor(eta, k, 0.05, 0.5, 0.5 * PI);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, across));
+
+    // Swirled stainless steel: the tangent map turns the brushing direction along u
+    let (w, h) = (64, 4);
+    let swirl = (0 .. w * h).map(|i| {
+        let angle = 2.0 * PI * 6.0 * (i % w) as f64 / w as f64;
+        vec3(0.5 + 0.5 * angle.cos(), 0.5 + 0.5 * angle.sin(), 0.0)
+    }).collect();
+    let swirl_map = Texture::image(Arc::new(ImageTexture::from_texels(w, h, WrapMode::Repeat, swirl)));
+    let (eta, k) = (vec3(2.7, 2.5, 2.3), vec3(3.8, 3.5, 3.2));
+    let steel = mats.add(Material::Conductor(Conductor::anisotropic(eta, k, 0.05, 0.4, 0.0).with_tangent_map(swirl_map)));
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, steel));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Measured BRDF next to its analytic counterparts
+fn build_world_16(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let measured = match MerlBrdf::load("gold-metallic-paint.binary") {
+        Ok(brdf) => mats.add_measured(Arc::new(brdf)),
+        Err(e) => {
+            println!("gold-metallic-paint.binary: {}", e);
+            mats.add_lambert(vec3(0.0, 1.0, 1.0))
+        }
+    };
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, measured));
+
+    let (eta, k) = metals::gold();
+    let gold = mats.add_conductor(eta, k, 0.3);
+    world.push(mk_sphere(-4.0, 1.0, 0.0, 1.0, gold));
+    let paint = Principled{metallic:0.6, roughness:0.35, clearcoat:1.0, ..Principled::new(Texture::constant(vec3(0.8, 0.6, 0.25)))};
+    let paint = mats.add_principled(paint);
+    world.push(mk_sphere(4.0, 1.0, 0.0, 1.0, paint));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Oren-Nayar rough diffuse against Lambert, lit from behind the camera
+fn build_world_17(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_oren_nayar_tex(Texture::noise(9, NoisePattern::Fbm, 6.0, vec3(0.35, 0.35, 0.33), vec3(0.6, 0.6, 0.58)), 0.6);
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let lamp = mats.add_diffuse_light(vec3(4.0, 4.0, 4.0));
+    world.push(mk_sphere(30.0, 8.0, 6.0, 8.0, lamp));
+
+    let lambert = mats.add_lambert(vec3(0.7, 0.4, 0.3));
+    world.push(mk_sphere(0.0, 1.0, -2.2, 1.0, lambert));
+    let clay = mats.add_oren_nayar(vec3(0.7, 0.4, 0.3), 0.5);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, clay));
+    let fabric = mats.add_oren_nayar(vec3(0.7, 0.4, 0.3), 1.2);
+    world.push(mk_sphere(0.0, 1.0, 2.2, 1.0, fabric));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats).with_background(Background::Solid(vec3(0.05, 0.05, 0.06)))
+}
+
+// Heterogeneous volumes: a cloud and a fireball built from noise grids.
+// DensityGrid::load reads the same data from a grid file.
+fn build_world_18(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let perlin = Perlin::new(10);
+    // Noise inside a soft sphere, t is the position in the unit cube of the grid
+    let puff = |t:Vec3, freq:f64| {
+        let r = (t - vec3(0.5, 0.5, 0.5)).length() * 2.0;
+        ((1.0 - r) * 2.0 + perlin.turb(t * freq, 5) - 0.6).max(0.0)
+    };
+
+    let cloud_bounds = Aabb::new(vec3(-1.5, 0.0, -3.0), vec3(1.5, 2.5, 0.0));
+    let cloud = Arc::new(DensityGrid::from_fn(48, 40, 48, cloud_bounds, |t| puff(t, 6.0)));
+    let cloud_mat = mats.add_volume(vec3(0.95, 0.95, 0.95), 0.6);
+    world.push(mk_volume(cloud, 8.0, cloud_mat));
+
+    let fire_bounds = Aabb::new(vec3(-1.2, 0.0, 0.8), vec3(1.2, 2.4, 3.2));
+    let smoke = Arc::new(DensityGrid::from_fn(40, 40, 40, fire_bounds, |t| puff(t, 4.0)));
+    // Hot core fading out towards the edge of the fireball
+    let temperature = Arc::new(DensityGrid::from_fn(40, 40, 40, fire_bounds, |t| (1.0 - (t - vec3(0.5, 0.45, 0.5)).length() * 2.5).max(0.0).powi(2)));
+    let fire_mat = mats.add_emissive_volume(vec3(0.3, 0.3, 0.3), 0.0, vec3(40.0, 12.0, 2.0), Some(temperature));
+    world.push(mk_volume(smoke, 6.0, fire_mat));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Modified Phong model (Lafortune and Willems 1994) written against the
+// public Bsdf trait, the way a material from another crate plugs in
+#[derive(Debug)]
+struct Phong{
+    diffuse : Vec3,
+    specular : Vec3,
+    exponent : f64
+}
+
+impl Bsdf for Phong{
+    // Cosine weighted hemisphere sampling, fine for the low exponents used here
+    fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let r1 = random_f64_normalized();
+        let r2 = random_f64_normalized();
+        let phi = 2.0 * PI * r1;
+        let local = vec3(r2.sqrt() * phi.cos(), r2.sqrt() * phi.sin(), (1.0 - r2).sqrt());
+        let wi = Onb::from_w(rec.normal).to_world(local);
+        let wo = unit_vector(r_in.dir) * -1.0;
+        let pdf = self.pdf(wo, wi, &rec);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(ScatterResult{attenuation:self.eval(wo, wi, &rec) * (1.0 / pdf), scattered:Ray3::new(rec.p, wi)})
+    }
+
+    fn eval(&self, wo:Vec3, wi:Vec3, rec:&HitRecord) -> Vec3{
+        let cos_i = dot(wi, rec.normal);
+        if cos_i <= 0.0 || dot(wo, rec.normal) <= 0.0 {
+            return Vec3::zeros();
+        }
+        let cos_r = dot((wo * -1.0).reflect(rec.normal), wi).max(0.0);
+        let lobe = (self.exponent + 2.0) / (2.0 * PI) * cos_r.powf(self.exponent);
+        (self.diffuse * (1.0 / PI) + self.specular * lobe) * cos_i
+    }
+
+    fn pdf(&self, _wo:Vec3, wi:Vec3, rec:&HitRecord) -> f64{
+        dot(wi, rec.normal).max(0.0) / PI
+    }
+}
+
+// Custom shading models next to the built in ones
+fn build_world_19(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.3, 0.1), vec3(0.9, 0.9, 0.9)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let plastic = mats.add_custom(Phong{diffuse:vec3(0.5, 0.1, 0.1), specular:vec3(0.3, 0.3, 0.3), exponent:40.0});
+    world.push(mk_sphere(0.0, 1.0, -2.2, 1.0, plastic));
+    let satin = mats.add_custom(Phong{diffuse:vec3(0.1, 0.2, 0.5), specular:vec3(0.4, 0.4, 0.4), exponent:8.0});
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, satin));
+    let lambert = mats.add_lambert(vec3(0.5, 0.1, 0.1));
+    world.push(mk_sphere(0.0, 1.0, 2.2, 1.0, lambert));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Materials from a Wavefront library, looked up by name. Missing names fall
+// back to gray, the way an importer without the library would show them.
+fn build_world_20(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.2, 0.2, 0.2), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let lib = match load_mtl("scene.mtl", &mut mats) {
+        Ok(lib) => lib,
+        Err(e) => {
+            println!("scene.mtl: {}", e);
+            MtlLibrary::default()
+        }
+    };
+    for w in lib.warnings.iter() {
+        println!("warn: {}", w);
+    }
+    let gray = mats.add_lambert(vec3(0.5, 0.5, 0.5));
+    for (i, name) in ["red_plastic", "brass", "glass", "lamp"].iter().enumerate() {
+        let mat = lib.get(name).unwrap_or(gray);
+        world.push(mk_sphere(0.0, 1.0, -3.3 + 2.2 * i as f64, 1.0, mat));
+    }
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+}
+
+// Small lamp reflected by metals of increasing fuzz, where light samples and
+// reflected rays are combined by MIS
+fn build_world_21(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert(vec3(0.5, 0.5, 0.5));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let lamp = mats.add_diffuse_light(vec3(60.0, 60.0, 60.0));
+    world.push(mk_sphere(4.0, 5.0, 3.0, 0.3, lamp));
+
+    let a = mats.add_metal(vec3(0.8, 0.8, 0.8), 0.05);
+    world.push(mk_sphere(0.0, 1.0, -2.2, 1.0, a));
+    let b = mats.add_metal(vec3(0.8, 0.6, 0.3), 0.2);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, b));
+    let c = mats.add_metal(vec3(0.8, 0.8, 0.8), 0.6);
+    world.push(mk_sphere(0.0, 1.0, 2.2, 1.0, c));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats).with_background(Background::Solid(vec3(0.02, 0.02, 0.03)))
+}
+
+// Lights without geometry: a warm point light, a spot on the left sphere and
+// a low sun with a soft edged shadow
+fn build_world_22(accel:AccelKind) -> Scene {
+    let mut mats = MaterialCollection::new();
+    let mut world = vec![];
+
+    let ground_material = mats.add_lambert_tex(Texture::checker(1.0, vec3(0.3, 0.3, 0.3), vec3(0.8, 0.8, 0.8)));
+    world.push(mk_sphere(0.0, -1000.0, 0.0, 1000.0, ground_material));
+
+    let white = mats.add_lambert(vec3(0.8, 0.8, 0.8));
+    world.push(mk_sphere(0.0, 1.0, -2.2, 1.0, white));
+    let steel = mats.add_metal(vec3(0.7, 0.7, 0.7), 0.3);
+    world.push(mk_sphere(0.0, 1.0, 0.0, 1.0, steel));
+    let clay = mats.add_oren_nayar(vec3(0.7, 0.4, 0.3), 0.5);
+    world.push(mk_sphere(0.0, 1.0, 2.2, 1.0, clay));
+
+    Scene::new(HittableObject::wrap_accel(accel, world), mats)
+        .with_background(Background::Solid(vec3(0.02, 0.02, 0.03)))
+        .with_light(Light::point(vec3(3.0, 2.5, 1.1), vec3(8.0, 6.0, 4.0)))
+        .with_light(Light::spot(vec3(1.0, 6.0, -2.2), vec3(-0.1, -1.0, 0.0), vec3(30.0, 40.0, 60.0), 20.0, 12.0))
+        .with_light(Light::sun(vec3(-1.0, -0.8, -1.5), vec3(0.8, 0.75, 0.6), 3.0))
 }
 
 fn do_draw(){
@@ -186,13 +679,40 @@ fn do_draw(){
         image_height : (image_width as f64 / aspect_ratio) as i32,
         //samples_per_pixel : 100,
         samples_per_pixel : 100,
-        max_depth : 50
+        max_depth : 50,
+        integrator : Integrator::Path
+        //integrator : Integrator::Spectral
+        //integrator : Integrator::Wavefront(integrator::WavefrontCfg::new(1 << 16, integrator::RaySort::Direction))
     };
 
     //let world_obj = HittableObject::wrap(world);
-    //let (world_obj, mats) = build_world_1();
-    let (world_obj, mats) = build_world_3();//fin
-    //let (world_obj, mats) = build_world_2();
+    let accel = AccelKind::List;
+    //let accel = AccelKind::Grid;
+    //let accel = AccelKind::KdTree;
+    let build_start = Instant::now();
+    //let scene = build_world_1(accel);
+    let scene = build_world_3(accel);//fin
+    //let scene = build_world_2(accel);
+    //let scene = build_world_4(accel);
+    //let scene = build_world_5(accel);
+    //let scene = build_world_6(accel);
+    //let scene = build_world_7(accel);
+    //let scene = build_world_8(accel);
+    //let scene = build_world_9(accel);
+    //let scene = build_world_10(accel);
+    //let scene = build_world_11(accel);
+    //let scene = build_world_12(accel);
+    //let scene = build_world_13(accel);
+    //let scene = build_world_14(accel);
+    //let scene = build_world_15(accel);
+    //let scene = build_world_16(accel);
+    //let scene = build_world_17(accel);
+    //let scene = build_world_18(accel);
+    //let scene = build_world_19(accel);
+    //let scene = build_world_20(accel);
+    //let scene = build_world_21(accel);
+    //let scene = build_world_22(accel);
+    println!("Build time ({:?}): {}ms", accel, build_start.elapsed().as_millis());
 
     // camera
     //let mut cam = Camera::default();
@@ -211,6 +731,7 @@ fn do_draw(){
     let aperture = 0.1;
 
     let mut cam = Camera::new(lookfrom, lookat, vup, 20.0, aspect_ratio, aperture, dist_to_focust);
+    //let mut cam = Camera::new(vec3(50.0, 52.0, 295.6), vec3(50.0, 40.8, 0.0), vup, 54.0, aspect_ratio, 0.0, 100.0); // build_world_6
 
     let f_w = (cfg.image_width - 1) as f64;
     let f_h = (cfg.image_height -1) as f64;
@@ -238,9 +759,12 @@ fn do_draw(){
     let start = Instant::now();
     bands.into_par_iter().for_each(|(i,band)| {
         pb.lock().unwrap().inc();
-        render_line(band,&cfg, &cam, &world_obj, &mats, i as i32);
+        match cfg.integrator {
+            Integrator::Path | Integrator::Spectral => render_line(band,&cfg, &cam, &scene, i as i32),
+            Integrator::Wavefront(opts) => wavefront::render_line(band,&cfg, &cam, &scene, i as i32, &opts)
+        }
     });
-    println!("Frame time: {}ms", start.elapsed().as_millis());
+    println!("Frame time ({:?}): {}ms", cfg.integrator, start.elapsed().as_millis());
     //let mut file = File::create("out.ppm").unwrap();
     write_color_file_vec("out.png", cfg.image_width as usize, cfg.image_height as usize, pixels);
 }
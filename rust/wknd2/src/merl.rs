@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, Read};
+use crate::raymath::{Vec3, vec3, dot, cross, unit_vector, Onb, Ray3, HitRecord, ScatterResult, random_f64_normalized, constants};
+use crate::microfacet::reflect_about;
+
+// Measured isotropic BRDF in the MERL format (Matusik et al. 2003).
+// Values are tabulated over the half / difference angles of Rusinkiewicz:
+// 90 theta_half bins on a square root scale, 90 theta_diff bins and 180
+// phi_diff bins, both linear.
+const THETA_H : usize = 90;
+const THETA_D : usize = 90;
+const PHI_D : usize = 180;
+const SCALE : [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+// Fraction of directions drawn from a cosine distribution instead of the
+// tabulated half vector distribution, to cover what the table misses
+const DIFFUSE_FRACTION : f64 = 0.1;
+
+pub struct MerlBrdf{
+    // RGB samples, theta_half major, then theta_diff, then phi_diff
+    data : Vec<Vec3>,
+    // Sampling CDF over theta_half bins
+    cdf : Vec<f64>
+}
+
+impl std::fmt::Debug for MerlBrdf{
+    fn fmt(&self, f:&mut std::fmt::Formatter) -> std::fmt::Result{
+        write!(f, "MerlBrdf {{ samples: {} }}", self.data.len())
+    }
+}
+
+fn invalid(msg:&str) -> io::Error{io::Error::new(io::ErrorKind::InvalidData, msg.to_string())}
+
+// Rotate v around a unit axis
+fn rotate(v:Vec3, axis:Vec3, angle:f64) -> Vec3{
+    let (s, c) = angle.sin_cos();
+    v * c + axis * (dot(axis, v) * (1.0 - c)) + cross(axis, v) * s
+}
+
+// Edge angle of theta_half bin i
+fn theta_h_edge(i:usize) -> f64{
+    let x = i as f64 / THETA_H as f64;
+    x * x * 0.5 * constants::PI_F64
+}
+
+impl MerlBrdf{
+    pub fn load(path:&str) -> io::Result<MerlBrdf>{
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < 12 {
+            return Err(invalid("truncated header"));
+        }
+        let mut dims = [0usize; 3];
+        for (i, d) in dims.iter_mut().enumerate() {
+            let v = i32::from_le_bytes([bytes[4 * i], bytes[4 * i + 1], bytes[4 * i + 2], bytes[4 * i + 3]]);
+            *d = usize::try_from(v).ok().filter(|&v| v > 0).ok_or_else(|| invalid("non-positive table dimension"))?;
+        }
+        let n = dims.iter().try_fold(1usize, |n, &d| n.checked_mul(d)).ok_or_else(|| invalid("table dimensions overflow"))?;
+        // Axes of the same total size in another order would be read wrongly
+        if dims != [THETA_H, THETA_D, PHI_D] {
+            return Err(invalid("unexpected table dimensions"));
+        }
+        let size = n.checked_mul(3 * 8).and_then(|s| s.checked_add(12)).ok_or_else(|| invalid("table dimensions overflow"))?;
+        if bytes.len() < size {
+            return Err(invalid("truncated data"));
+        }
+        let value = |c:usize, i:usize| {
+            let o = 12 + (c * n + i) * 8;
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[o .. o + 8]);
+            // Missing measurements are stored as negative values
+            (f64::from_le_bytes(b) * SCALE[c]).max(0.0)
+        };
+        let data = (0 .. n).map(|i| vec3(value(0, i), value(1, i), value(2, i))).collect();
+        Ok(MerlBrdf::from_table(data))
+    }
+
+    fn from_table(data:Vec<Vec3>) -> MerlBrdf{
+        // Tabulated fit of the half vector distribution: average luminance per
+        // theta_half bin times the projected solid angle of the bin.
+        let mut cdf = vec![0.0; THETA_H + 1];
+        for i in 0 .. THETA_H {
+            let row = &data[i * THETA_D * PHI_D .. (i + 1) * THETA_D * PHI_D];
+            let mean = row.iter().map(|c| 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z).sum::<f64>() / row.len() as f64;
+            let (t0, t1) = (theta_h_edge(i), theta_h_edge(i + 1));
+            let solid_angle = 0.5 * (t1.sin().powi(2) - t0.sin().powi(2));
+            cdf[i + 1] = cdf[i] + mean.max(1e-6) * solid_angle;
+        }
+        let total = cdf[THETA_H];
+        for c in cdf.iter_mut() {
+            *c /= total;
+        }
+        MerlBrdf{data, cdf}
+    }
+
+    fn at(&self, h:usize, d:usize, p:usize) -> Vec3{
+        self.data[(h * THETA_D + d) * PHI_D + p]
+    }
+
+    // BRDF for directions in the local shading frame, trilinearly interpolated
+    pub fn eval(&self, wo:Vec3, wi:Vec3) -> Vec3{
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::zeros();
+        }
+        let h = unit_vector(wo + wi);
+        let theta_h = h.z.clamp(-1.0, 1.0).acos();
+        let phi_h = h.y.atan2(h.x);
+        let d = rotate(rotate(wi, vec3(0.0, 0.0, 1.0), -phi_h), vec3(0.0, 1.0, 0.0), -theta_h);
+        let theta_d = d.z.clamp(-1.0, 1.0).acos();
+        // Reciprocity makes the table symmetric in phi_diff with period pi
+        let phi_d = d.y.atan2(d.x).rem_euclid(constants::PI_F64);
+
+        let xh = ((theta_h / (0.5 * constants::PI_F64)).sqrt() * THETA_H as f64).min((THETA_H - 1) as f64);
+        let xd = (theta_d / (0.5 * constants::PI_F64) * THETA_D as f64).min((THETA_D - 1) as f64);
+        let xp = phi_d / constants::PI_F64 * PHI_D as f64;
+        let (h0, d0, p0) = (xh as usize, xd as usize, xp as usize % PHI_D);
+        let (h1, d1, p1) = ((h0 + 1).min(THETA_H - 1), (d0 + 1).min(THETA_D - 1), (p0 + 1) % PHI_D);
+        let (fh, fd, fp) = (xh - h0 as f64, xd - d0 as f64, xp - xp.floor());
+        let lerp = |a:Vec3, b:Vec3, t:f64| a * (1.0 - t) + b * t;
+        let plane = |h:usize| {
+            let lo = lerp(self.at(h, d0, p0), self.at(h, d0, p1), fp);
+            let hi = lerp(self.at(h, d1, p0), self.at(h, d1, p1), fp);
+            lerp(lo, hi, fd)
+        };
+        lerp(plane(h0), plane(h1), fh)
+    }
+
+    // Solid angle density of a half vector at theta_half
+    fn pdf_half(&self, theta_h:f64) -> f64{
+        let i = (((theta_h / (0.5 * constants::PI_F64)).max(0.0).sqrt() * THETA_H as f64) as usize).min(THETA_H - 1);
+        let (t0, t1) = (theta_h_edge(i), theta_h_edge(i + 1));
+        let p_theta = (self.cdf[i + 1] - self.cdf[i]) / (t1 - t0);
+        p_theta / (2.0 * constants::PI_F64 * theta_h.sin().max(1e-6))
+    }
+
+    pub fn pdf(&self, wo:Vec3, wi:Vec3) -> f64{
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = unit_vector(wo + wi);
+        let tabulated = self.pdf_half(h.z.clamp(-1.0, 1.0).acos()) / (4.0 * dot(wo, h));
+        (1.0 - DIFFUSE_FRACTION) * tabulated + DIFFUSE_FRACTION * wi.z / constants::PI_F64
+    }
+
+    fn sample_half(&self) -> Vec3{
+        let u = random_f64_normalized();
+        let i = self.cdf.partition_point(|&c| c <= u).clamp(1, THETA_H) - 1;
+        let t = (u - self.cdf[i]) / (self.cdf[i + 1] - self.cdf[i]).max(1e-12);
+        let theta = theta_h_edge(i) + t * (theta_h_edge(i + 1) - theta_h_edge(i));
+        let phi = 2.0 * constants::PI_F64 * random_f64_normalized();
+        vec3(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let frame = Onb::from_w(rec.normal);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let wi = if random_f64_normalized() < DIFFUSE_FRACTION {
+            let r1 = random_f64_normalized();
+            let r2 = random_f64_normalized();
+            let phi = 2.0 * constants::PI_F64 * r1;
+            vec3(r2.sqrt() * phi.cos(), r2.sqrt() * phi.sin(), (1.0 - r2).sqrt())
+        } else {
+            reflect_about(wo, self.sample_half())
+        };
+        let pdf = self.pdf(wo, wi);
+        if wi.z <= 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        let attenuation = self.eval(wo, wi) * (wi.z / pdf);
+        Some(ScatterResult{attenuation, scattered:Ray3::new(rec.p, frame.to_world(wi))})
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::fs;
+
+    fn load_bytes(name:&str, bytes:&[u8]) -> io::Result<MerlBrdf>{
+        let path = std::env::temp_dir().join(format!("wknd-merl-{}-{}.binary", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let brdf = MerlBrdf::load(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        brdf
+    }
+
+    fn header(dims:[i32; 3]) -> Vec<u8>{
+        dims.iter().flat_map(|d| d.to_le_bytes()).collect()
+    }
+
+    fn error(name:&str, bytes:&[u8]) -> String{
+        let e = load_bytes(name, bytes).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.to_string()
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected(){
+        assert_eq!(error("short", &header([90, 90, 180])[.. 8]), "truncated header");
+        assert_eq!(error("negative", &header([-90, -90, 180])), "non-positive table dimension");
+        assert_eq!(error("zero", &header([0, 90, 180])), "non-positive table dimension");
+        assert_eq!(error("swapped", &header([180, 90, 90])), "unexpected table dimensions");
+        assert_eq!(error("huge", &header([i32::MAX, i32::MAX, i32::MAX])), "table dimensions overflow");
+    }
+
+    #[test]
+    fn truncated_data_is_rejected(){
+        let mut bytes = header([90, 90, 180]);
+        bytes.extend(vec![0u8; 3 * 8 * 1000]);
+        assert_eq!(error("truncated", &bytes), "truncated data");
+    }
+}
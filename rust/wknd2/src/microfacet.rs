@@ -0,0 +1,241 @@
+use num::complex::Complex64;
+use crate::raymath::{Vec3, vec3, dot, cross, unit_vector, Onb, Ray3, HitRecord, ScatterResult, random_f64_normalized, constants};
+use crate::texture::Texture;
+
+// Microfacet BSDFs
+// All directions are in the local shading frame, z along the normal.
+
+// GGX / Trowbridge-Reitz distribution
+#[derive(Debug, Copy, Clone)]
+pub struct Ggx{
+    pub alpha_x : f64,
+    pub alpha_y : f64
+}
+
+// Below this alpha the surface is treated as perfectly smooth.
+const SMOOTH_ALPHA : f64 = 1e-3;
+
+impl Ggx{
+    pub fn new(alpha_x:f64, alpha_y:f64) -> Ggx{Ggx{alpha_x, alpha_y}}
+    // Perceptual roughness is squared into alpha.
+    pub fn from_roughness(roughness:f64) -> Ggx{
+        let a = roughness * roughness;
+        Ggx{alpha_x:a, alpha_y:a}
+    }
+
+    pub fn is_smooth(&self) -> bool{
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    pub fn d(&self, wm:Vec3) -> f64{
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let e = x * x + y * y + wm.z * wm.z;
+        1.0 / (constants::PI_F64 * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    pub fn lambda(&self, w:Vec3) -> f64{
+        if w.z == 0.0 {
+            return f64::MAX;
+        }
+        let a2 = (self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2);
+        0.5 * (-1.0 + (1.0 + a2 / (w.z * w.z)).sqrt())
+    }
+
+    // Smith masking
+    pub fn g1(&self, w:Vec3) -> f64{1.0 / (1.0 + self.lambda(w))}
+
+    // Height-correlated Smith masking-shadowing
+    pub fn g(&self, wo:Vec3, wi:Vec3) -> f64{1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))}
+
+    // Density of visible normals from wo
+    pub fn pdf(&self, wo:Vec3, wm:Vec3) -> f64{
+        if wo.z == 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) / wo.z.abs() * self.d(wm) * dot(wo, wm).abs()
+    }
+
+    // Heitz 2018, "Sampling the GGX Distribution of Visible Normals"
+    pub fn sample_wm(&self, wo:Vec3, u1:f64, u2:f64) -> Vec3{
+        let wo = if wo.z < 0.0 {wo * -1.0} else {wo};
+        let vh = unit_vector(vec3(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z));
+        let lensq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if lensq > 0.0 {vec3(-vh.y, vh.x, 0.0) / lensq.sqrt()} else {vec3(1.0, 0.0, 0.0)};
+        let t2 = cross(vh, t1);
+        let r = u1.sqrt();
+        let phi = 2.0 * constants::PI_F64 * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        unit_vector(vec3(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)))
+    }
+
+    pub fn sample_wm_random(&self, wo:Vec3) -> Vec3{
+        self.sample_wm(wo, random_f64_normalized(), random_f64_normalized())
+    }
+}
+
+pub fn reflect_about(wo:Vec3, n:Vec3) -> Vec3{
+    n * (2.0 * dot(wo, n)) - wo
+}
+
+// Refract wo (pointing away from the surface) through n. eta is the ratio of the
+// transmitted to the incident index. None on total internal reflection.
+pub fn refract_about(wo:Vec3, n:Vec3, eta:f64) -> Option<Vec3>{
+    let cos_i = dot(n, wo);
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = sin2_i / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(wo * (-1.0 / eta) + n * (cos_i / eta - cos_t))
+}
+
+// Exact unpolarized Fresnel reflectance of a dielectric interface.
+// eta is the ratio of the transmitted to the incident index for cos_i > 0.
+pub fn fresnel_dielectric(cos_i:f64, eta:f64) -> f64{
+    let mut cos_i = cos_i.clamp(-1.0, 1.0);
+    let mut eta = eta;
+    if cos_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_i = -cos_i;
+    }
+    let sin2_i = 1.0 - cos_i * cos_i;
+    let sin2_t = sin2_i / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).max(0.0).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parl * r_parl + r_perp * r_perp)
+}
+
+// Fresnel reflectance of a conductor with complex index eta + ik
+pub fn fresnel_complex(cos_i:f64, eta:f64, k:f64) -> f64{
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let eta = Complex64::new(eta, k);
+    let sin2_i = Complex64::new(1.0 - cos_i * cos_i, 0.0);
+    let sin2_t = sin2_i / (eta * eta);
+    let cos_t = (Complex64::new(1.0, 0.0) - sin2_t).sqrt();
+    let ci = Complex64::new(cos_i, 0.0);
+    let r_parl = (eta * ci - cos_t) / (eta * ci + cos_t);
+    let r_perp = (ci - eta * cos_t) / (ci + eta * cos_t);
+    0.5 * (r_parl.norm_sqr() + r_perp.norm_sqr())
+}
+
+pub fn fresnel_conductor(cos_i:f64, eta:Vec3, k:Vec3) -> Vec3{
+    vec3(fresnel_complex(cos_i, eta.x, k.x), fresnel_complex(cos_i, eta.y, k.y), fresnel_complex(cos_i, eta.z, k.z))
+}
+
+// Complex indices of refraction sampled at roughly 650, 550 and 450 nm
+pub mod metals{
+    use crate::raymath::{Vec3, vec3};
+    pub fn gold() -> (Vec3, Vec3){(vec3(0.143, 0.374, 1.442), vec3(3.983, 2.385, 1.603))}
+    pub fn silver() -> (Vec3, Vec3){(vec3(0.155, 0.117, 0.138), vec3(4.828, 3.122, 2.147))}
+    pub fn copper() -> (Vec3, Vec3){(vec3(0.200, 0.924, 1.102), vec3(3.912, 2.452, 2.142))}
+    pub fn aluminium() -> (Vec3, Vec3){(vec3(1.657, 0.880, 0.521), vec3(9.224, 6.270, 4.837))}
+}
+
+// Rough conductor
+// Roughness may differ along the surface tangent (dpdu) and bitangent. The
+// tangent is rotated by `rotation` radians and, when a tangent map is given,
+// by the direction it encodes in its first two channels as 0.5 * d + 0.5.
+#[derive(Debug)]
+pub struct Conductor{
+    eta : Vec3,
+    k : Vec3,
+    distrib : Ggx,
+    rotation : f64,
+    tangent_map : Option<Texture>
+}
+
+impl Conductor{
+    pub fn new(eta:Vec3, k:Vec3, roughness:f64) -> Conductor{
+        Conductor{eta, k, distrib:Ggx::from_roughness(roughness), rotation:0.0, tangent_map:None}
+    }
+    pub fn anisotropic(eta:Vec3, k:Vec3, roughness_t:f64, roughness_b:f64, rotation:f64) -> Conductor{
+        let distrib = Ggx::new(roughness_t * roughness_t, roughness_b * roughness_b);
+        Conductor{eta, k, distrib, rotation, tangent_map:None}
+    }
+    pub fn with_tangent_map(self, map:Texture) -> Conductor{Conductor{tangent_map:Some(map), ..self}}
+
+    fn frame(&self, rec:&HitRecord) -> Onb{
+        if self.distrib.alpha_x == self.distrib.alpha_y {
+            return Onb::from_w(rec.normal);
+        }
+        let mut angle = self.rotation;
+        if let Some(map) = &self.tangent_map {
+            let d = map.value(rec.u, rec.v, rec.p) * 2.0 - Vec3::ones();
+            if d.x != 0.0 || d.y != 0.0 {
+                angle += d.y.atan2(d.x);
+            }
+        }
+        let base = Onb::from_wt(rec.normal, rec.dpdu);
+        Onb::from_wt(rec.normal, base.u * angle.cos() + base.v * angle.sin())
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let frame = self.frame(&rec);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        if self.distrib.is_smooth() {
+            let wi = vec3(-wo.x, -wo.y, wo.z);
+            let attenuation = fresnel_conductor(wo.z, self.eta, self.k);
+            return Some(ScatterResult{attenuation, scattered:Ray3::new(rec.p, frame.to_world(wi))});
+        }
+        let wm = self.distrib.sample_wm_random(wo);
+        let wi = reflect_about(wo, wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        // f * cos / pdf for visible normal sampling
+        let weight = self.distrib.g(wo, wi) / self.distrib.g1(wo);
+        let attenuation = fresnel_conductor(dot(wo, wm), self.eta, self.k) * weight;
+        Some(ScatterResult{attenuation, scattered:Ray3::new(rec.p, frame.to_world(wi))})
+    }
+}
+
+// Rough dielectric (Walter et al. 2007)
+#[derive(Debug)]
+pub struct RoughDielectric{
+    ir : f64,
+    distrib : Ggx
+}
+
+impl RoughDielectric{
+    pub fn new(ir:f64, roughness:f64) -> RoughDielectric{
+        RoughDielectric{ir, distrib:Ggx::from_roughness(roughness)}
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let frame = Onb::from_w(rec.normal);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let eta = if rec.front_face {self.ir} else {1.0 / self.ir};
+        let wm = if self.distrib.is_smooth() {vec3(0.0, 0.0, 1.0)} else {self.distrib.sample_wm_random(wo)};
+        let f = fresnel_dielectric(dot(wo, wm), eta);
+        // Reflection and transmission are picked with probability F and 1 - F, so
+        // the Fresnel terms cancel. Radiance is not rescaled by 1/eta^2, which
+        // cancels over a path that enters and leaves the medium.
+        let (wi, reflected) = match refract_about(wo, wm, eta) {
+            Some(wt) if random_f64_normalized() >= f => (wt, false),
+            _ => (reflect_about(wo, wm), true)
+        };
+        if (reflected && wi.z <= 0.0) || (!reflected && wi.z >= 0.0) {
+            return None;
+        }
+        let weight = if self.distrib.is_smooth() {1.0} else {self.distrib.g(wo, wi) / self.distrib.g1(wo)};
+        Some(ScatterResult{attenuation:Vec3::ones() * weight, scattered:Ray3::new(rec.p, frame.to_world(wi))})
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::raymath::{Vec3, vec3, Material, MaterialCollection, MaterialId};
+use crate::texture::{Texture, WrapMode};
+use crate::normalmap::NormalMap;
+
+// Wavefront material libraries (.mtl)
+// Every newmtl block becomes one material in the MaterialCollection, looked up
+// by name. illum picks the kind: glass for the refraction modes, Metal for the
+// mirror modes and Lambertian otherwise. Ke turns any of them into a light.
+// Statements the renderer has no use for, and statements with malformed
+// values, are skipped with a warning.
+// There is no OBJ loader yet, so nothing follows mtllib / usemtl statements:
+// callers look materials up by name and assign them to primitives themselves.
+
+// Statements of one newmtl block, defaults as in the MTL spec
+#[derive(Debug)]
+struct MtlDesc{
+    name : String,
+    kd : Vec3,
+    // None until a Ks statement
+    ks : Option<Vec3>,
+    ke : Vec3,
+    // Transmission filter of glass, the color after a unit distance
+    tf : Vec3,
+    ns : f64,
+    ni : f64,
+    d : f64,
+    illum : u32,
+    map_kd : Option<Texture>,
+    map_ks : Option<Texture>,
+    map_ke : Option<Texture>,
+    map_d : Option<Texture>,
+    bump : Option<(Texture, f64)>
+}
+
+impl MtlDesc{
+    fn new(name:&str) -> MtlDesc{
+        MtlDesc{
+            name:name.to_string(), kd:vec3(0.8, 0.8, 0.8), ks:None, ke:Vec3::zeros(), tf:Vec3::ones(),
+            ns:0.0, ni:1.0, d:1.0, illum:2, map_kd:None, map_ks:None, map_ke:None, map_d:None, bump:None
+        }
+    }
+
+    fn is_glass(&self) -> bool{matches!(self.illum, 4 | 6 | 7 | 9)}
+
+    fn material(&self) -> Material{
+        let tex = |map:&Option<Texture>, col:Vec3| map.clone().unwrap_or(Texture::Constant(col));
+        let base = if self.ke.length2() > 0.0 || self.map_ke.is_some() {
+            Material::mk_diffuse_light_tex(tex(&self.map_ke, self.ke))
+        } else if self.is_glass() {
+            if self.tf == Vec3::ones() {
+                Material::mk_dielectric(self.ni)
+            } else {
+                Material::mk_dielectric_absorbing(self.ni, self.tf, 1.0)
+            }
+        } else if matches!(self.illum, 3 | 5 | 8) {
+            // Width of a Phong lobe with exponent Ns
+            let fuzz = (2.0 / (self.ns.max(0.0) + 2.0)).sqrt().min(1.0);
+            // Exporters often write only Kd for a mirror, it stands in for a missing Ks
+            let albedo = match (&self.map_ks, self.ks) {
+                (None, None) => tex(&self.map_kd, self.kd),
+                (map, ks) => tex(map, ks.unwrap_or(Vec3::zeros()))
+            };
+            Material::mk_metal_tex(albedo, fuzz)
+        } else {
+            Material::mk_lambert_tex(tex(&self.map_kd, self.kd))
+        };
+        let base = match &self.bump {
+            Some((height, scale)) => base.with_normal_map(NormalMap::bump(height.clone(), *scale)),
+            None => base
+        };
+        // Glass is see-through by refraction already
+        match &self.map_d {
+            Some(alpha) => base.with_alpha(alpha.clone()),
+            None if self.d < 1.0 && !self.is_glass() => base.with_alpha(Texture::Constant(Vec3::ones() * self.d)),
+            None => base
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MtlLibrary{
+    pub materials : HashMap<String, MaterialId>,
+    // One line per statement that was skipped, "file:line: message"
+    pub warnings : Vec<String>
+}
+
+impl MtlLibrary{
+    pub fn get(&self, name:&str) -> Option<MaterialId>{self.materials.get(name).copied()}
+}
+
+fn parse_f64(arg:&str, at:&str) -> Result<f64, String>{
+    arg.parse().map_err(|_| format!("{}: expected a number, found `{}`", at, arg))
+}
+
+// "r g b" or a single gray value
+fn parse_color(args:&[&str], at:&str, keyword:&str) -> Result<Vec3, String>{
+    match args {
+        [r, g, b] => Ok(vec3(parse_f64(r, at)?, parse_f64(g, at)?, parse_f64(b, at)?)),
+        [v] => Ok(Vec3::ones() * parse_f64(v, at)?),
+        _ => Err(format!("{}: only rgb colors are supported for {}", at, keyword))
+    }
+}
+
+fn parse_scalar(args:&[&str], at:&str, keyword:&str) -> Result<f64, String>{
+    match args {
+        [v] => parse_f64(v, at),
+        [] => Err(format!("{}: missing value for {}", at, keyword)),
+        _ => Err(format!("{}: expected one value for {}", at, keyword))
+    }
+}
+
+// Texture statement: options first, then the file name relative to the
+// library. Returns the texture and the -bm bump multiplier.
+fn parse_map(args:&[&str], dir:&Path, linear:bool, at:&str, warnings:&mut Vec<String>) -> Option<(Texture, f64)>{
+    let mut wrap = WrapMode::Repeat;
+    let mut bm = 1.0;
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with('-') {
+        let opt = args[i];
+        i += 1;
+        match opt {
+            "-bm" | "-clamp" if i >= args.len() => {
+                warnings.push(format!("{}: missing value for {}", at, opt));
+            },
+            "-bm" => {
+                match parse_f64(args[i], at) {
+                    Ok(v) => bm = v,
+                    Err(msg) => warnings.push(format!("{} for -bm, using 1", msg))
+                }
+                i += 1;
+            },
+            "-clamp" => {
+                match args[i] {
+                    "on" => wrap = WrapMode::Clamp,
+                    "off" => wrap = WrapMode::Repeat,
+                    v => warnings.push(format!("{}: expected on or off for -clamp, found `{}`", at, v))
+                }
+                i += 1;
+            },
+            // Up to three numbers
+            "-o" | "-s" | "-t" => {
+                warnings.push(format!("{}: texture option {} is not supported", at, opt));
+                let n = args[i ..].iter().take(3).take_while(|a| a.parse::<f64>().is_ok()).count();
+                i += n;
+            },
+            "-mm" => {
+                warnings.push(format!("{}: texture option {} is not supported", at, opt));
+                i += 2;
+            },
+            _ => {
+                warnings.push(format!("{}: texture option {} is not supported", at, opt));
+                i += 1;
+            }
+        }
+    }
+    if i >= args.len() {
+        warnings.push(format!("{}: missing texture file name", at));
+        return None;
+    }
+    let file = dir.join(args[i ..].join(" "));
+    let path = file.to_string_lossy();
+    let tex = if linear {Texture::load_image_linear(&path, wrap)} else {Texture::load_image(&path, wrap)};
+    match tex {
+        Ok(tex) => Some((tex, bm)),
+        Err(e) => {
+            warnings.push(format!("{}: {}: {}", at, path, e));
+            None
+        }
+    }
+}
+
+// newmtl blocks of a library, texture paths relative to dir. path only
+// labels the warnings.
+fn parse_mtl(text:&str, path:&str, dir:&Path, warnings:&mut Vec<String>) -> Vec<MtlDesc>{
+    let mut descs:Vec<MtlDesc> = vec![];
+    for (n, line) in text.lines().enumerate() {
+        // Comments start at a token beginning with #, so file names may contain one
+        let tokens:Vec<&str> = line.split_whitespace().take_while(|t| !t.starts_with('#')).collect();
+        let (keyword, args) = match tokens.split_first() {
+            Some((k, a)) => (*k, a),
+            None => continue
+        };
+        let at = format!("{}:{}", path, n + 1);
+        if keyword == "newmtl" {
+            descs.push(MtlDesc::new(&args.join(" ")));
+            continue;
+        }
+        let desc = match descs.last_mut() {
+            Some(desc) => desc,
+            None => {
+                warnings.push(format!("{}: `{}` before the first newmtl", at, keyword));
+                continue;
+            }
+        };
+        let color = || parse_color(args, &at, keyword);
+        let scalar = || parse_scalar(args, &at, keyword);
+        let parsed = match keyword {
+            "Kd" => color().map(|c| desc.kd = c),
+            "Ks" => color().map(|c| desc.ks = Some(c)),
+            "Ke" => color().map(|c| desc.ke = c),
+            "Tf" => color().map(|c| desc.tf = c),
+            "Ns" => scalar().map(|v| desc.ns = v),
+            "Ni" => scalar().map(|v| desc.ni = v),
+            "d" => scalar().map(|v| desc.d = v.clamp(0.0, 1.0)),
+            "Tr" => scalar().map(|v| desc.d = 1.0 - v.clamp(0.0, 1.0)),
+            "illum" => scalar().map(|v| desc.illum = v as u32),
+            "map_Kd" => {
+                desc.map_kd = parse_map(args, dir, false, &at, warnings).map(|m| m.0);
+                Ok(())
+            },
+            "map_Ks" => {
+                desc.map_ks = parse_map(args, dir, false, &at, warnings).map(|m| m.0);
+                Ok(())
+            },
+            "map_Ke" => {
+                desc.map_ke = parse_map(args, dir, false, &at, warnings).map(|m| m.0);
+                Ok(())
+            },
+            "map_d" => {
+                desc.map_d = parse_map(args, dir, true, &at, warnings).map(|m| m.0);
+                Ok(())
+            },
+            // Heights in texture units, -bm scales them
+            "bump" | "map_bump" | "map_Bump" => {
+                desc.bump = parse_map(args, dir, true, &at, warnings);
+                Ok(())
+            },
+            // Ambient has no counterpart in a path tracer
+            "Ka" | "map_Ka" => Ok(()),
+            _ => Err(format!("{}: unsupported statement `{}`", at, keyword))
+        };
+        if let Err(msg) = parsed {
+            warnings.push(msg);
+        }
+    }
+    descs
+}
+
+// Parse a material library and add its materials to `mats`
+pub fn load_mtl(path:&str, mats:&mut MaterialCollection) -> io::Result<MtlLibrary>{
+    let text = fs::read_to_string(path)?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut lib = MtlLibrary::default();
+    let descs = parse_mtl(&text, path, dir, &mut lib.warnings);
+    for desc in descs {
+        let id = mats.add(desc.material());
+        if lib.materials.insert(desc.name.clone(), id).is_some() {
+            lib.warnings.push(format!("{}: material {} is defined twice, using the last one", path, desc.name));
+        }
+    }
+    Ok(lib)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::raymath::{Ray3, HitRecord};
+
+    fn parse(text:&str) -> (Vec<MtlDesc>, Vec<String>){
+        let mut warnings = vec![];
+        let descs = parse_mtl(text, "t.mtl", Path::new(""), &mut warnings);
+        (descs, warnings)
+    }
+
+    fn parse_one(text:&str) -> (MtlDesc, Vec<String>){
+        let (mut descs, warnings) = parse(&format!("newmtl m\n{}", text));
+        assert_eq!(descs.len(), 1);
+        (descs.pop().unwrap(), warnings)
+    }
+
+    type Check = fn(&MtlDesc) -> bool;
+
+    #[test]
+    fn statements_set_their_fields(){
+        let cases : &[(&str, Check)] = &[
+            ("Kd 0.1 0.2 0.3", |d| d.kd == vec3(0.1, 0.2, 0.3)),
+            ("Kd 0.5", |d| d.kd == vec3(0.5, 0.5, 0.5)),
+            ("Ks 1 0 0", |d| d.ks == Some(vec3(1.0, 0.0, 0.0))),
+            ("Ke 4 4 4", |d| d.ke == vec3(4.0, 4.0, 4.0)),
+            ("Tf 0.9 0.8 0.7", |d| d.tf == vec3(0.9, 0.8, 0.7)),
+            ("Ns 250", |d| d.ns == 250.0),
+            ("Ni 1.45", |d| d.ni == 1.45),
+            ("d 0.25", |d| d.d == 0.25),
+            ("d 3", |d| d.d == 1.0),
+            ("Tr 0.25", |d| d.d == 0.75),
+            ("illum 7", |d| d.illum == 7),
+            ("Ka 1 1 1", |d| d.kd == vec3(0.8, 0.8, 0.8)),
+            ("Kd 0.1 0.2 0.3 # red", |d| d.kd == vec3(0.1, 0.2, 0.3)),
+            ("# Kd 0 0 0", |d| d.kd == vec3(0.8, 0.8, 0.8))
+        ];
+        for (text, check) in cases {
+            let (desc, warnings) = parse_one(text);
+            assert!(check(&desc), "{}: {:?}", text, desc);
+            assert!(warnings.is_empty(), "{}: {:?}", text, warnings);
+        }
+    }
+
+    #[test]
+    fn malformed_statements_are_skipped_with_a_warning(){
+        let cases = [
+            ("Kd 0.1 x 0.3", "t.mtl:2: expected a number, found `x`"),
+            ("Kd 0.1 0.2", "t.mtl:2: only rgb colors are supported for Kd"),
+            ("Tf spectral f.spd", "t.mtl:2: only rgb colors are supported for Tf"),
+            ("Ns", "t.mtl:2: missing value for Ns"),
+            ("Ni 1.5 2", "t.mtl:2: expected one value for Ni"),
+            ("d half", "t.mtl:2: expected a number, found `half`"),
+            ("illum two", "t.mtl:2: expected a number, found `two`"),
+            ("sharpness 60", "t.mtl:2: unsupported statement `sharpness`")
+        ];
+        for (text, warning) in cases {
+            // The statement after the bad one still applies
+            let (desc, warnings) = parse_one(&format!("{}\nNs 10", text));
+            assert_eq!(warnings, [warning], "{}", text);
+            assert_eq!(desc.kd, vec3(0.8, 0.8, 0.8));
+            assert_eq!((desc.tf, desc.ni, desc.d, desc.illum, desc.ns), (Vec3::ones(), 1.0, 1.0, 2, 10.0));
+        }
+        let (descs, warnings) = parse("Kd 1 1 1\nnewmtl a\nnewmtl b\n");
+        assert_eq!(descs.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(warnings, ["t.mtl:1: `Kd` before the first newmtl"]);
+    }
+
+    #[test]
+    fn illum_picks_the_material_kind(){
+        let kind = |text:&str| match parse_one(text).0.material() {
+            Material::Lambertian(_) => "lambert",
+            Material::Metal(_) => "metal",
+            Material::Dielectric(_) => "glass",
+            Material::DiffuseLight(_) => "light",
+            Material::Masked{..} => "masked",
+            _ => "other"
+        };
+        assert_eq!(kind("illum 2"), "lambert");
+        assert_eq!(kind("illum 3"), "metal");
+        assert_eq!(kind("illum 7"), "glass");
+        assert_eq!(kind("illum 7\nTf 0.5 0.5 0.5"), "glass");
+        assert_eq!(kind("illum 3\nKe 1 1 1"), "light");
+        assert_eq!(kind("d 0.5"), "masked");
+        assert_eq!(kind("illum 4\nd 0.5"), "glass");
+    }
+
+    #[test]
+    fn mirrors_without_ks_use_kd(){
+        let mats = MaterialCollection::new();
+        let rec = HitRecord{normal:vec3(0.0, 1.0, 0.0), t:1.0, front_face:true, ..HitRecord::new_default(0)};
+        let r_in = Ray3::new(vec3(-1.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0));
+        let albedo = |text:&str| {
+            let m = parse_one(text).0.material();
+            assert!(matches!(m, Material::Metal(_)));
+            loop {
+                if let Some(s) = m.scatter(r_in, rec, &mats) {
+                    return s.attenuation;
+                }
+            }
+        };
+        assert_eq!(albedo("illum 3\nNs 900\nKd 0.9 0.5 0.1"), vec3(0.9, 0.5, 0.1));
+        assert_eq!(albedo("illum 3\nNs 900\nKd 0.9 0.5 0.1\nKs 0.2 0.3 0.4"), vec3(0.2, 0.3, 0.4));
+        assert_eq!(albedo("illum 3\nNs 900\nKd 0.9 0.5 0.1\nKs 0 0 0"), Vec3::zeros());
+    }
+
+    #[test]
+    fn texture_maps_and_options(){
+        let dir = std::env::temp_dir().join(format!("wknd-mtl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::new(2, 2).save(dir.join("tile#2.png")).unwrap();
+        let mut warnings = vec![];
+        let text = "newmtl m\n\
+            map_Kd tile#2.png\n\
+            map_Ks -clamp on tile#2.png # comment\n\
+            map_d -o 0.5 0.5 tile#2.png\n\
+            bump -bm 0.2 tile#2.png\n\
+            newmtl n\n\
+            bump -bm high -clamp maybe tile#2.png\n\
+            map_Kd missing.png\n\
+            map_Ke -bm\n";
+        let descs = parse_mtl(text, "t.mtl", &dir, &mut warnings);
+        fs::remove_dir_all(&dir).unwrap();
+        let (m, n) = (&descs[0], &descs[1]);
+        assert!(m.map_kd.is_some() && m.map_ks.is_some() && m.map_d.is_some());
+        assert_eq!(m.bump.as_ref().map(|b| b.1), Some(0.2));
+        assert_eq!(n.bump.as_ref().map(|b| b.1), Some(1.0));
+        assert!(n.map_kd.is_none() && n.map_ke.is_none());
+        assert_eq!(warnings[0], "t.mtl:4: texture option -o is not supported");
+        assert_eq!(warnings[1], "t.mtl:7: expected a number, found `high` for -bm, using 1");
+        assert_eq!(warnings[2], "t.mtl:7: expected on or off for -clamp, found `maybe`");
+        assert!(warnings[3].starts_with("t.mtl:8: ") && warnings[3].contains("missing.png"), "{}", warnings[3]);
+        assert_eq!(warnings[4 ..], ["t.mtl:9: missing value for -bm", "t.mtl:9: missing texture file name"]);
+    }
+}
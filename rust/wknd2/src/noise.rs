@@ -0,0 +1,158 @@
+use crate::raymath::{Vec3, vec3, dot, unit_vector, SplitMix};
+
+// Gradient (Perlin) noise
+const POINT_COUNT : usize = 256;
+
+#[derive(Debug)]
+pub struct Perlin{
+    gradients : Vec<Vec3>,
+    perm_x : Vec<usize>,
+    perm_y : Vec<usize>,
+    perm_z : Vec<usize>
+}
+
+impl Perlin{
+    // The same seed always gives the same noise, so renders are repeatable.
+    pub fn new(seed:u64) -> Perlin{
+        let mut rng = SplitMix(seed);
+        let mut gradients = Vec::with_capacity(POINT_COUNT);
+        let mut coord = || 2.0 * rng.next_f64() - 1.0;
+        while gradients.len() < POINT_COUNT {
+            let v = vec3(coord(), coord(), coord());
+            let l2 = v.length2();
+            if l2 > 1e-6 && l2 <= 1.0 {
+                gradients.push(unit_vector(v));
+            }
+        }
+        let perm_x = Perlin::permutation(&mut rng);
+        let perm_y = Perlin::permutation(&mut rng);
+        let perm_z = Perlin::permutation(&mut rng);
+        Perlin{gradients, perm_x, perm_y, perm_z}
+    }
+
+    fn permutation(rng:&mut SplitMix) -> Vec<usize>{
+        let mut p : Vec<usize> = (0 .. POINT_COUNT).collect();
+        for i in (1 .. POINT_COUNT).rev() {
+            let target = rng.below(i + 1);
+            p.swap(i, target);
+        }
+        p
+    }
+
+    // Noise in roughly [-1, 1]
+    pub fn noise(&self, p:Vec3) -> f64{
+        let fx = p.x.floor();
+        let fy = p.y.floor();
+        let fz = p.z.floor();
+        let (u, v, w) = (p.x - fx, p.y - fy, p.z - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+        let mask = POINT_COUNT as i64 - 1;
+
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+        let mut accum = 0.0;
+        for di in 0 .. 2 {
+            for dj in 0 .. 2 {
+                for dk in 0 .. 2 {
+                    let idx = self.perm_x[((i + di) & mask) as usize]
+                        ^ self.perm_y[((j + dj) & mask) as usize]
+                        ^ self.perm_z[((k + dk) & mask) as usize];
+                    let (a, b, c) = (di as f64, dj as f64, dk as f64);
+                    let weight = vec3(u - a, v - b, w - c);
+                    accum += (a * uu + (1.0 - a) * (1.0 - uu))
+                        * (b * vv + (1.0 - b) * (1.0 - vv))
+                        * (c * ww + (1.0 - c) * (1.0 - ww))
+                        * dot(self.gradients[idx], weight);
+                }
+            }
+        }
+        accum
+    }
+
+    // Fractional Brownian motion, signed
+    pub fn fbm(&self, p:Vec3, octaves:i32) -> f64{
+        let mut accum = 0.0;
+        let mut q = p;
+        let mut weight = 1.0;
+        for _ in 0 .. octaves {
+            accum += weight * self.noise(q);
+            weight *= 0.5;
+            q = q * 2.0;
+        }
+        accum
+    }
+
+    // Sum of absolute octaves
+    pub fn turb(&self, p:Vec3, octaves:i32) -> f64{
+        let mut accum = 0.0;
+        let mut q = p;
+        let mut weight = 1.0;
+        for _ in 0 .. octaves {
+            accum += weight * self.noise(q).abs();
+            weight *= 0.5;
+            q = q * 2.0;
+        }
+        accum
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NoisePattern{
+    Fbm,
+    Turbulence,
+    Marble,
+    Wood,
+    Cloud
+}
+
+const OCTAVES : i32 = 7;
+
+fn smoothstep(e0:f64, e1:f64, x:f64) -> f64{
+    let t = ((x - e0) / (e1 - e0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Blend factor in [0, 1] between a pattern's two colors at p.
+pub fn pattern_value(noise:&Perlin, pattern:NoisePattern, scale:f64, p:Vec3) -> f64{
+    let sp = p * scale;
+    let t = match pattern {
+        NoisePattern::Fbm => 0.5 * (1.0 + noise.fbm(sp, OCTAVES)),
+        NoisePattern::Turbulence => noise.turb(sp, OCTAVES),
+        NoisePattern::Marble => 0.5 * (1.0 + f64::sin(sp.z + 10.0 * noise.turb(sp, OCTAVES))),
+        NoisePattern::Wood => {
+            let rings = (sp.x * sp.x + sp.z * sp.z).sqrt() * 4.0 + 2.0 * noise.fbm(sp, 3);
+            rings - rings.floor()
+        },
+        NoisePattern::Cloud => smoothstep(-0.1, 0.5, noise.fbm(sp, OCTAVES))
+    };
+    t.clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn splitmix_matches_reference(){
+        // First outputs of the reference SplitMix64 for seed 0
+        let mut rng = SplitMix(0);
+        assert_eq!(rng.next_u64(), 0xe220a8397b1dcdaf);
+        assert_eq!(rng.next_u64(), 0x6e789e6aa1b965f4);
+    }
+
+    #[test]
+    fn permutations_are_fixed_by_the_seed(){
+        let p = Perlin::new(42);
+        assert_eq!(&p.perm_x[.. 8], &[88, 195, 219, 136, 174, 77, 226, 239]);
+        assert_eq!(&p.perm_y[.. 4], &[85, 234, 212, 230]);
+        assert_eq!(&p.perm_z[.. 4], &[165, 134, 121, 187]);
+        assert!((p.gradients[0] - vec3(0.511505386566318, -0.7201281363546009, -0.46880455068620086)).length() < 1e-12);
+        assert!((p.noise(vec3(1.3, 2.7, -0.4)) + 0.062777901183).abs() < 1e-9);
+        for perm in [&p.perm_x, &p.perm_y, &p.perm_z] {
+            let mut sorted = perm.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0 .. POINT_COUNT).collect::<Vec<_>>());
+        }
+    }
+}
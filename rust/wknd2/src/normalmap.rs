@@ -0,0 +1,62 @@
+use crate::raymath::{Vec3, dot, cross, unit_vector, Ray3, HitRecord};
+use crate::texture::Texture;
+
+// Shading normal perturbation
+// Applied to the HitRecord before the wrapped material scatters. The
+// geometric tangents come from HitRecord::dpdu and dpdv.
+#[derive(Debug, Clone)]
+pub enum NormalMap{
+    // Tangent space normal map, stored as 0.5 * n + 0.5 in a linear texture.
+    // strength scales the tangential part.
+    Tangent{map:Texture, strength:f64},
+    // Scalar height field read from the first channel, in world units times scale
+    Bump{height:Texture, scale:f64}
+}
+
+// Step in uv used for the bump map finite differences
+const BUMP_DELTA : f64 = 0.0005;
+// Smallest cosine allowed between the shading normal and the viewer
+const MIN_COS : f64 = 1e-3;
+
+impl NormalMap{
+    pub fn tangent(map:Texture, strength:f64) -> NormalMap{NormalMap::Tangent{map, strength}}
+    pub fn bump(height:Texture, scale:f64) -> NormalMap{NormalMap::Bump{height, scale}}
+
+    pub fn perturb(&self, r_in:&Ray3, rec:HitRecord) -> HitRecord{
+        if rec.dpdu.length2() == 0.0 {
+            return rec;
+        }
+        let n = rec.normal;
+        let ns = match self {
+            NormalMap::Tangent{map, strength} => {
+                let c = map.value(rec.u, rec.v, rec.p) * 2.0 - Vec3::ones();
+                let t = unit_vector(rec.dpdu - n * dot(n, rec.dpdu));
+                // Keep the handedness of the outward frame when the normal was flipped
+                let b = if rec.front_face {cross(n, t)} else {cross(t, n)};
+                t * (c.x * strength) + b * (c.y * strength) + n * c.z
+            },
+            NormalMap::Bump{height, scale} => {
+                let h = |u:f64, v:f64, p:Vec3| height.value(u, v, p).x * scale;
+                let h0 = h(rec.u, rec.v, rec.p);
+                let hu = h(rec.u + BUMP_DELTA, rec.v, rec.p + rec.dpdu * BUMP_DELTA);
+                let hv = h(rec.u, rec.v + BUMP_DELTA, rec.p + rec.dpdv * BUMP_DELTA);
+                let dpdu = rec.dpdu + n * ((hu - h0) / BUMP_DELTA);
+                let dpdv = rec.dpdv + n * ((hv - h0) / BUMP_DELTA);
+                let ns = cross(dpdu, dpdv);
+                if dot(ns, n) < 0.0 {ns * -1.0} else {ns}
+            }
+        };
+        if ns.length2() == 0.0 {
+            return rec;
+        }
+        let mut ns = unit_vector(ns);
+        // A shading normal facing away from the viewer would make every
+        // scattered ray leave below the surface. Tilt it back towards the viewer.
+        let wo = unit_vector(r_in.dir) * -1.0;
+        let cos_o = dot(ns, wo);
+        if cos_o < MIN_COS {
+            ns = unit_vector(ns + wo * (MIN_COS - cos_o));
+        }
+        HitRecord{normal:ns, ..rec}
+    }
+}
@@ -0,0 +1,256 @@
+use crate::raymath::{Vec3, vec3, dot, unit_vector, lerp3, Onb, Ray3, HitRecord, ScatterResult, random_f64_normalized, constants};
+use crate::microfacet::{Ggx, reflect_about, refract_about, fresnel_dielectric};
+use crate::texture::Texture;
+
+// Principled material
+// A Disney / OpenPBR style uber material. Each scatter picks one lobe with
+// probability proportional to its estimated contribution and divides by that
+// probability, so the estimate stays unbiased.
+#[derive(Debug, Clone)]
+pub struct Principled{
+    pub base_color : Texture,
+    pub metallic : f64,
+    pub roughness : f64,
+    // Dielectric reflectance, 0.5 is F0 = 0.04 (IOR 1.5)
+    pub specular : f64,
+    pub sheen : f64,
+    pub clearcoat : f64,
+    pub clearcoat_roughness : f64,
+    pub transmission : f64,
+    pub subsurface : f64
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Lobe{
+    Diffuse,
+    Specular,
+    Clearcoat,
+    Transmission
+}
+
+const LOBES : [Lobe; 4] = [Lobe::Diffuse, Lobe::Specular, Lobe::Clearcoat, Lobe::Transmission];
+const MIN_ALPHA : f64 = 1e-3;
+
+pub fn luminance(c:Vec3) -> f64{
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+fn schlick_weight(cos:f64) -> f64{
+    (1.0 - cos.clamp(0.0, 1.0)).powi(5)
+}
+
+fn schlick(f0:Vec3, cos:f64) -> Vec3{
+    f0 + (Vec3::ones() - f0) * schlick_weight(cos)
+}
+
+fn cosine_sample() -> Vec3{
+    let r1 = random_f64_normalized();
+    let r2 = random_f64_normalized();
+    let phi = 2.0 * constants::PI_F64 * r1;
+    let r = r2.sqrt();
+    vec3(r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt())
+}
+
+// Index of the lobe xi in [0, 1) falls on. Lobes with zero probability are
+// never picked; if rounding leaves xi past the cumulative sum, the last lobe
+// that can be picked is used.
+fn pick_lobe(probs:&[f64; 4], xi:f64) -> usize{
+    let mut acc = 0.0;
+    for (i, &p) in probs.iter().enumerate() {
+        acc += p;
+        if p > 0.0 && xi < acc {
+            return i;
+        }
+    }
+    probs.iter().rposition(|&p| p > 0.0).unwrap_or(1)
+}
+
+impl Principled{
+    pub fn new(base_color:Texture) -> Principled{
+        Principled{
+            base_color,
+            metallic:0.0,
+            roughness:0.5,
+            specular:0.5,
+            sheen:0.0,
+            clearcoat:0.0,
+            clearcoat_roughness:0.03,
+            transmission:0.0,
+            subsurface:0.0
+        }
+    }
+
+    fn f0_dielectric(&self) -> f64{0.08 * self.specular}
+
+    // IOR matching the specular reflectance at normal incidence
+    pub fn ior(&self) -> f64{
+        let s = self.f0_dielectric().clamp(0.0, 0.99).sqrt();
+        (1.0 + s) / (1.0 - s)
+    }
+
+    fn spec_distrib(&self) -> Ggx{
+        let a = (self.roughness * self.roughness).max(MIN_ALPHA);
+        Ggx::new(a, a)
+    }
+
+    fn coat_distrib(&self) -> Ggx{
+        let a = (self.clearcoat_roughness * self.clearcoat_roughness).max(MIN_ALPHA);
+        Ggx::new(a, a)
+    }
+
+    fn spec_f0(&self, base:Vec3) -> Vec3{
+        lerp3(Vec3::ones() * self.f0_dielectric(), base, self.metallic)
+    }
+
+    // Energy left for the layers below the dielectric specular and the clearcoat
+    // (albedo scaling), so the diffuse base does not add to light already reflected.
+    fn layer_scales(&self, cos_o:f64) -> (f64, f64){
+        let spec = self.f0_dielectric() + (1.0 - self.f0_dielectric()) * schlick_weight(cos_o);
+        let coat = 0.25 * self.clearcoat * (0.04 + 0.96 * schlick_weight(cos_o));
+        (1.0 - spec, 1.0 - coat)
+    }
+
+    fn lobe_probs(&self, base:Vec3, cos_o:f64) -> [f64; 4]{
+        let dielectric = 1.0 - self.metallic;
+        let diffuse = dielectric * (1.0 - self.transmission) * (luminance(base) + self.sheen * 0.1);
+        let specular = luminance(schlick(self.spec_f0(base), cos_o));
+        let coat = 0.25 * self.clearcoat * luminance(schlick(Vec3::ones() * 0.04, cos_o));
+        let trans = dielectric * self.transmission * (1.0 - fresnel_dielectric(cos_o, self.ior()));
+        let sum = diffuse + specular + coat + trans;
+        if sum <= 0.0 {
+            return [0.0, 1.0, 0.0, 0.0];
+        }
+        [diffuse / sum, specular / sum, coat / sum, trans / sum]
+    }
+
+    // BSDF times cosine for a lobe, both directions above the surface
+    fn eval_lobe(&self, lobe:Lobe, base:Vec3, wo:Vec3, wi:Vec3) -> Vec3{
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::zeros();
+        }
+        let wh = unit_vector(wo + wi);
+        let (base_scale, coat_scale) = self.layer_scales(wo.z);
+        match lobe {
+            Lobe::Diffuse => {
+                let cos_d = dot(wi, wh);
+                let fl = schlick_weight(wi.z);
+                let fv = schlick_weight(wo.z);
+                let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+                let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+                let fss90 = cos_d * cos_d * self.roughness;
+                let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+                let ss = 1.25 * (fss * (1.0 / (wi.z + wo.z) - 0.5) + 0.5);
+                let diffuse = base * ((fd + (ss - fd) * self.subsurface) / constants::PI_F64);
+                let sheen = Vec3::ones() * (self.sheen * schlick_weight(cos_d));
+                (diffuse + sheen) * ((1.0 - self.metallic) * (1.0 - self.transmission) * base_scale * coat_scale * wi.z)
+            },
+            Lobe::Specular => {
+                let d = self.spec_distrib();
+                schlick(self.spec_f0(base), dot(wo, wh)) * (coat_scale * d.d(wh) * d.g(wo, wi) / (4.0 * wo.z))
+            },
+            Lobe::Clearcoat => {
+                let d = self.coat_distrib();
+                schlick(Vec3::ones() * 0.04, dot(wo, wh)) * (0.25 * self.clearcoat * d.d(wh) * d.g(wo, wi) / (4.0 * wo.z))
+            },
+            Lobe::Transmission => Vec3::zeros()
+        }
+    }
+
+    fn pdf_lobe(&self, lobe:Lobe, wo:Vec3, wi:Vec3) -> f64{
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let wh = unit_vector(wo + wi);
+        match lobe {
+            Lobe::Diffuse => wi.z / constants::PI_F64,
+            Lobe::Specular => self.spec_distrib().pdf(wo, wh) / (4.0 * dot(wo, wh)),
+            Lobe::Clearcoat => self.coat_distrib().pdf(wo, wh) / (4.0 * dot(wo, wh)),
+            Lobe::Transmission => 0.0
+        }
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let frame = Onb::from_w(rec.normal);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let base = self.base_color.value(rec.u, rec.v, rec.p);
+        let probs = self.lobe_probs(base, wo.z);
+
+        let k = pick_lobe(&probs, random_f64_normalized());
+        let lobe = LOBES[k];
+        let p = probs[k];
+
+        let (wi, weight) = match lobe {
+            Lobe::Diffuse => {
+                let wi = cosine_sample();
+                let pdf = self.pdf_lobe(lobe, wo, wi);
+                if pdf <= 0.0 {
+                    return None;
+                }
+                (wi, self.eval_lobe(lobe, base, wo, wi) / pdf)
+            },
+            Lobe::Specular | Lobe::Clearcoat => {
+                let d = if lobe == Lobe::Specular {self.spec_distrib()} else {self.coat_distrib()};
+                let wm = d.sample_wm_random(wo);
+                let wi = reflect_about(wo, wm);
+                if wi.z <= 0.0 {
+                    return None;
+                }
+                // Visible normal sampling leaves F * G2 / G1
+                let g = d.g(wo, wi) / d.g1(wo);
+                let f = if lobe == Lobe::Specular {
+                    schlick(self.spec_f0(base), dot(wo, wm)) * self.layer_scales(wo.z).1
+                } else {
+                    schlick(Vec3::ones() * 0.04, dot(wo, wm)) * (0.25 * self.clearcoat)
+                };
+                (wi, f * g)
+            },
+            Lobe::Transmission => {
+                let d = self.spec_distrib();
+                let wm = d.sample_wm_random(wo);
+                let ior = self.ior();
+                let eta = if rec.front_face {ior} else {1.0 / ior};
+                let wi = refract_about(wo, wm, eta)?;
+                if wi.z >= 0.0 {
+                    return None;
+                }
+                let f = 1.0 - fresnel_dielectric(dot(wo, wm), eta);
+                let tint = base * ((1.0 - self.metallic) * self.transmission * f * self.layer_scales(wo.z).1);
+                (wi, tint * (d.g(wo, wi) / d.g1(wo)))
+            }
+        };
+        Some(ScatterResult{attenuation:weight / p, scattered:Ray3::new(rec.p, frame.to_world(wi))})
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn zero_probability_lobes_are_never_picked(){
+        let probs = [0.0, 0.7, 0.3, 0.0];
+        assert_eq!(pick_lobe(&probs, 0.0), 1);
+        assert_eq!(pick_lobe(&probs, 0.69), 1);
+        assert_eq!(pick_lobe(&probs, 0.7), 2);
+        // Sum short of one by rounding
+        assert_eq!(pick_lobe(&[0.5, 0.5 - 1e-12, 0.0, 0.0], 1.0 - 1e-13), 1);
+        assert_eq!(pick_lobe(&[0.0, 0.0, 0.0, 1.0], 0.0), 3);
+    }
+
+    #[test]
+    fn metal_scatter_weights_are_finite(){
+        let mut m = Principled::new(Texture::Constant(vec3(0.9, 0.6, 0.3)));
+        m.metallic = 1.0;
+        let rec = HitRecord{normal:vec3(0.0, 0.0, 1.0), t:1.0, front_face:true, ..HitRecord::new_default(0)};
+        let r_in = Ray3::new(vec3(0.3, 0.0, 1.0), vec3(-0.3, 0.0, -1.0));
+        for _ in 0 .. 1000 {
+            if let Some(s) = m.scatter(r_in, rec) {
+                let a = s.attenuation;
+                assert!(a.x.is_finite() && a.y.is_finite() && a.z.is_finite());
+            }
+        }
+    }
+}
@@ -6,6 +6,21 @@ use num::{NumCast, cast, Num};
 use std::{rc::Rc, cmp, io::BufWriter};
 use ordered_float::OrderedFloat;
 use image::{GenericImage, GenericImageView, ImageBuffer, RgbImage};
+use crate::accel::{UniformGrid, KdTree};
+use crate::texture::Texture;
+use crate::microfacet::{Conductor, RoughDielectric, fresnel_dielectric};
+use crate::principled::Principled;
+use crate::spectral::{IorCurve, LAMBDA_D};
+use crate::thinfilm::{ThinFilm, conductor_ior};
+use crate::normalmap::NormalMap;
+use crate::layered::{Mix, Coated};
+use crate::subsurface::Subsurface;
+use crate::merl::MerlBrdf;
+use crate::volume::{Volume, VolumeMaterial, DensityGrid};
+use crate::light::{Light, collect_lights};
+use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 //rand
 
 use rand::{Rng, thread_rng};
@@ -105,6 +120,22 @@ impl Vec3 {
     pub fn random_unit_vector()->Vec3{
         unit_vector(Vec3::random_in_unit_sphere())        
     }
+
+    pub fn axis(&self, i:usize) -> f64{
+        match i {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z
+        }
+    }
+}
+
+pub fn vmin(a:Vec3, b:Vec3) -> Vec3{
+    Vec3{x:minf(a.x, b.x), y:minf(a.y, b.y), z:minf(a.z, b.z)}
+}
+
+pub fn vmax(a:Vec3, b:Vec3) -> Vec3{
+    Vec3{x:maxf(a.x, b.x), y:maxf(a.y, b.y), z:maxf(a.z, b.z)}
 }
 
 impl Mul<f64> for Vec3 {
@@ -164,6 +195,41 @@ pub fn cross(a:Vec3, b:Vec3) -> Vec3{
     Vec3{x:x, y:y, z:z}
 }
 
+// Orthonormal basis around w
+#[derive(Debug, Copy, Clone)]
+pub struct Onb{
+    pub u : Vec3,
+    pub v : Vec3,
+    pub w : Vec3
+}
+
+impl Onb{
+    // Duff et al. 2017, "Building an Orthonormal Basis, Revisited"
+    pub fn from_w(n:Vec3) -> Onb{
+        let sign = if n.z >= 0.0 {1.0} else {-1.0};
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        let u = vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+        let v = vec3(b, sign + n.y * n.y * a, -n.y);
+        Onb{u, v, w:n}
+    }
+    // Frame around n with u along the tangent t projected into the plane of n
+    pub fn from_wt(n:Vec3, t:Vec3) -> Onb{
+        let tp = t - n * dot(n, t);
+        if tp.length2() < 1e-12 {
+            return Onb::from_w(n);
+        }
+        let u = unit_vector(tp);
+        Onb{u, v:cross(n, u), w:n}
+    }
+    pub fn to_world(self, a:Vec3) -> Vec3{
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+    pub fn to_local(self, a:Vec3) -> Vec3{
+        vec3(dot(a, self.u), dot(a, self.v), dot(a, self.w))
+    }
+}
+
 pub fn minf(a:f64, b:f64) -> f64{
     //*cmp::min(OrderedFloat(a), OrderedFloat(b)).deref()
     if a < b {a} else {b}
@@ -186,7 +252,9 @@ pub fn refract(uv:Vec3, n:Vec3, etai_over_etat:f64) -> Vec3{
 //
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Ray3{
-    pub orig : Vec3, pub dir : Vec3
+    pub orig : Vec3, pub dir : Vec3,
+    // Wavelength in nm carried by spectral paths, 0 in RGB mode
+    pub lambda : f64
 }
 
 impl Ray3{
@@ -196,7 +264,10 @@ impl Ray3{
         self.orig + (self.dir * t)
     }
     pub fn new(origin:Vec3, direction:Vec3) -> Ray3{
-        Ray3{orig: origin, dir: direction}
+        Ray3{orig: origin, dir: direction, lambda: 0.0}
+    }
+    pub fn with_lambda(self, lambda:f64) -> Ray3{
+        Ray3{lambda, ..self}
     }
 }
 
@@ -231,12 +302,25 @@ pub fn hit_sphere(center:Vec3, radius:f64, r:&Ray3) -> f64{
 
 // sampling cfg
 #[derive(Debug, Copy, Clone)]
-pub struct SamplingCfg{
-    t_min : f64, t_max : f64
+pub struct SamplingCfg<'a>{
+    pub t_min : f64, pub t_max : f64,
+    // Materials consulted for opacity masks, None ignores them
+    pub mats : Option<&'a MaterialCollection>
 }
 
-impl SamplingCfg{
-    pub fn new(minv:f64, maxv:f64) -> SamplingCfg{SamplingCfg{t_min:minv, t_max:maxv}}
+impl<'a> SamplingCfg<'a>{
+    pub fn new(minv:f64, maxv:f64) -> SamplingCfg<'a>{SamplingCfg{t_min:minv, t_max:maxv, mats:None}}
+    pub fn with_masks(self, mats:&'a MaterialCollection) -> SamplingCfg<'a>{SamplingCfg{mats:Some(mats), ..self}}
+    // True if the hit lies on a cut out part of its material
+    pub fn masked(&self, r:&Ray3, rec:&HitRecord) -> bool{
+        match self.mats {
+            Some(mats) => {
+                let alpha = mats.materials[rec.mat].opacity(rec, mats);
+                alpha < 1.0 && (alpha <= 0.0 || alpha < hit_hash(r, rec))
+            },
+            None => false
+        }
+    }
     pub fn inrange(&self, t:f64)->bool{t >= self.t_min && t <= self.t_max}
     pub fn inrange32(&self, t:f32)->bool{
         let tl = t as f64;
@@ -244,32 +328,189 @@ impl SamplingCfg{
     }
 }
 
+// Hash of a ray, for decisions that must come out the same every time the
+// same ray is tested against a primitive.
+pub fn ray_seed(r:&Ray3) -> u64{
+    let mut hasher = DefaultHasher::new();
+    for c in [r.orig.x, r.orig.y, r.orig.z, r.dir.x, r.dir.y, r.dir.z] {
+        c.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// SplitMix64, a small generator whose output is fixed by its seed on every
+// platform and version, unlike rand's StdRng
+#[derive(Debug, Copy, Clone)]
+pub struct SplitMix(pub u64);
+
+impl SplitMix{
+    pub fn next_u64(&mut self) -> u64{
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64{
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    // Uniform in [0, n)
+    pub fn below(&mut self, n:usize) -> usize{
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+}
+
+// Uniform number in [0, 1) fixed per ray and hit distance, so that a
+// partially opaque surface gives the same answer every time the same ray is
+// tested against it, while the two sides of a sphere are cut out independently.
+fn hit_hash(r:&Ray3, rec:&HitRecord) -> f64{
+    SplitMix(ray_seed(r) ^ rec.t.to_bits()).next_f64()
+}
+
 // Material
 pub struct ScatterResult{
     pub attenuation :Vec3,
     pub scattered : Ray3
 }
 
+// ScatterResult with the solid angle density of the scattered direction,
+// None for delta lobes and materials without a pdf
+pub struct ScatterSample{
+    pub attenuation : Vec3,
+    pub scattered : Ray3,
+    pub pdf : Option<f64>
+}
+
+// Shading model implemented outside this crate, stored as Material::Custom.
+// Directions are unit vectors in world space pointing away from the surface,
+// wo towards the viewer and wi towards the light.
+pub trait Bsdf: std::fmt::Debug + Send + Sync{
+    // Sample wi, the attenuation is eval / pdf
+    fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>;
+    // BSDF times |cos| of wi against the shading normal, zero for delta lobes
+    fn eval(&self, wo:Vec3, wi:Vec3, rec:&HitRecord) -> Vec3;
+    // Solid angle density with which scatter picks wi, zero for delta lobes
+    fn pdf(&self, wo:Vec3, wi:Vec3, rec:&HitRecord) -> f64;
+    fn emitted(&self, _rec:&HitRecord) -> Vec3{Vec3::zeros()}
+    // Models made of delta lobes only have no use for light samples
+    fn is_specular(&self) -> bool{false}
+}
+
 #[derive(Debug)]
-struct Lambertian{albedo:Vec3}
+pub struct Lambertian{albedo:Texture}
 impl Lambertian{
     fn scatter(&self, rec:HitRecord) -> ScatterResult{
         let mut scatter_direction = rec.normal + Vec3::random_unit_vector();
         if scatter_direction.near_zero() {
             scatter_direction = rec.normal;
         }
-        ScatterResult{attenuation:self.albedo, scattered : Ray3::new(rec.p, scatter_direction)}
+        ScatterResult{attenuation:self.albedo.value(rec.u, rec.v, rec.p), scattered : Ray3::new(rec.p, scatter_direction)}
+    }
+    fn eval(&self, wi:Vec3, rec:&HitRecord) -> Vec3{
+        self.albedo.value(rec.u, rec.v, rec.p) * (dot(wi, rec.normal).max(0.0) / constants::PI_F64)
+    }
+}
+// Oren-Nayar rough diffuse, sigma is the standard deviation of the facet
+// slope angle in radians. sigma = 0 is Lambertian.
+#[derive(Debug)]
+pub struct OrenNayar{
+    albedo:Texture,
+    a:f64,
+    b:f64
+}
+impl OrenNayar{
+    fn new(albedo:Texture, sigma:f64) -> OrenNayar{
+        let s2 = sigma * sigma;
+        OrenNayar{albedo, a:1.0 - s2 / (2.0 * (s2 + 0.33)), b:0.45 * s2 / (s2 + 0.09)}
+    }
+    // BRDF times pi over albedo, directions in the local frame
+    fn shape(&self, wo:Vec3, wi:Vec3) -> f64{
+        let sin_o = (1.0 - wo.z * wo.z).max(0.0).sqrt();
+        let sin_i = (1.0 - wi.z * wi.z).max(0.0).sqrt();
+        let cos_phi = if sin_o > 1e-4 && sin_i > 1e-4 {
+            ((wi.x * wo.x + wi.y * wo.y) / (sin_i * sin_o)).max(0.0)
+        } else {
+            0.0
+        };
+        let (sin_alpha, tan_beta) = if wi.z.abs() > wo.z.abs() {
+            (sin_o, sin_i / wi.z.abs())
+        } else {
+            (sin_i, sin_o / wo.z.abs())
+        };
+        self.a + self.b * cos_phi * sin_alpha * tan_beta
+    }
+    fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let frame = Onb::from_w(rec.normal);
+        let wo = frame.to_local(unit_vector(r_in.dir) * -1.0);
+        let mut scatter_direction = rec.normal + Vec3::random_unit_vector();
+        if scatter_direction.near_zero() {
+            scatter_direction = rec.normal;
+        }
+        let wi = frame.to_local(unit_vector(scatter_direction));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return None;
+        }
+        // Cosine sampling leaves albedo times the Oren-Nayar factor
+        let attenuation = self.albedo.value(rec.u, rec.v, rec.p) * self.shape(wo, wi);
+        Some(ScatterResult{attenuation, scattered:Ray3::new(rec.p, frame.to_world(wi))})
+    }
+    fn eval(&self, wo:Vec3, wi:Vec3, rec:&HitRecord) -> Vec3{
+        let frame = Onb::from_w(rec.normal);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::zeros();
+        }
+        self.albedo.value(rec.u, rec.v, rec.p) * (self.shape(wo, wi) * wi.z / constants::PI_F64)
     }
 }
 #[derive(Debug)]
-struct Metal{
-    albedo:Vec3,
-    fuzz:f64
+pub struct Metal{
+    albedo:Texture,
+    fuzz:f64,
+    film:Option<ThinFilm>
 }
 impl Metal{
-    pub fn new(albedo:Vec3, f:f64) -> Metal {
+    pub fn new(albedo:Texture, f:f64) -> Metal {
         let fuzz = if f < 1.0 {f} else{1.0};
-        Metal{albedo:albedo, fuzz:fuzz}
+        Metal{albedo:albedo, fuzz:fuzz, film:None}
+    }
+    pub fn with_film(self, film:ThinFilm) -> Metal{Metal{film:Some(film), ..self}}
+    fn reflectance(&self, r_in:&Ray3, rec:&HitRecord) -> Vec3{
+        let albedo = self.albedo.value(rec.u, rec.v, rec.p);
+        match &self.film {
+            // The albedo is read as both the normal reflectance and the edge tint of the metal
+            Some(film) => {
+                let (eta, k) = conductor_ior(albedo, albedo);
+                let cos_i = dot(unit_vector(r_in.dir) * -1.0, rec.normal);
+                film.reflectance(rec, cos_i, 1.0, eta, k, r_in.lambda)
+            },
+            None => albedo
+        }
+    }
+    // Density of the normalized direction reflected + fuzz * (point in the
+    // unit ball): the ball's density integrated along the ray through it
+    fn pdf(&self, reflected:Vec3, wi:Vec3) -> f64{
+        let c = dot(wi, reflected);
+        let disc = c * c - 1.0 + self.fuzz * self.fuzz;
+        if disc < 0.0 {
+            return 0.0;
+        }
+        let t1 = c + disc.sqrt();
+        let t0 = (c - disc.sqrt()).max(0.0);
+        if t1 <= 0.0 {
+            return 0.0;
+        }
+        (t1.powi(3) - t0.powi(3)) / (4.0 * constants::PI_F64 * self.fuzz.powi(3))
+    }
+    // scatter's attenuation is the reflectance, so the BSDF times cosine is
+    // the reflectance times the sampling density
+    fn eval(&self, wo:Vec3, wi:Vec3, lambda:f64, rec:&HitRecord) -> Vec3{
+        if dot(wi, rec.normal) <= 0.0 {
+            return Vec3::zeros();
+        }
+        let r_in = Ray3::new(rec.p, wo * -1.0).with_lambda(lambda);
+        self.reflectance(&r_in, rec) * self.pdf((wo * -1.0).reflect(rec.normal), wi)
     }
     fn scatter(&self, r_in:Ray3,rec:HitRecord) -> Option<ScatterResult>{
         let reflected = unit_vector(r_in.dir).reflect(rec.normal);
@@ -277,7 +518,7 @@ impl Metal{
 
         if scattered.dir * rec.normal > 0.0
         {
-            let res = ScatterResult{attenuation:self.albedo, scattered : scattered};
+            let res = ScatterResult{attenuation:self.reflectance(&r_in, &rec), scattered : scattered};
             Some(res)
         }
         else {
@@ -288,44 +529,157 @@ impl Metal{
 
 #[derive(Debug)]
 pub struct Dielectric{
-    ir : f64
+    ir : f64,
+    // Absorption coefficient per unit distance inside the medium
+    absorption : Vec3,
+    // Wavelength dependent index, used by spectral paths
+    dispersion : Option<IorCurve>,
+    film : Option<ThinFilm>
 }
 
 impl Dielectric{
-    pub fn new(ir:f64) ->Dielectric{Dielectric{ir:ir}}
-    // Schlick reflectance
-    fn reflectance(cosine:f64, ref_idx:f64) -> f64{
-        let r0 = (1.0 - ref_idx) / (1.0 + ref_idx).powf(2.0);
-        r0 + (1.0 - r0) * f64::powf(1.0 - cosine, 5.0)
+    pub fn new(ir:f64) ->Dielectric{Dielectric{ir, absorption:Vec3::zeros(), dispersion:None, film:None}}
+    // Medium that transmits `color` after light travels `distance` through it
+    pub fn with_absorption(ir:f64, color:Vec3, distance:f64) -> Dielectric{
+        let sigma = |c:f64| -maxf(c, 1e-6).ln() / distance;
+        Dielectric{ir, absorption:vec3(sigma(color.x), sigma(color.y), sigma(color.z)), dispersion:None, film:None}
     }
-    fn scatter(&self, r_in:Ray3,rec:HitRecord) -> Option<ScatterResult>{
-        let attenuation = vec3(1.0, 1.0, 1.0);
-        let refraction_ratio = if rec.front_face {1.0 / self.ir} else {self.ir};
+    // RGB paths use the index at the sodium D line
+    pub fn dispersive(curve:IorCurve) -> Dielectric{
+        Dielectric{ir:curve.eval(LAMBDA_D), absorption:Vec3::zeros(), dispersion:Some(curve), film:None}
+    }
+    fn ior_at(&self, lambda:f64) -> f64{
+        match self.dispersion {
+            Some(curve) if lambda > 0.0 => curve.eval(lambda),
+            _ => self.ir
+        }
+    }
+    pub fn with_film(self, film:ThinFilm) -> Dielectric{Dielectric{film:Some(film), ..self}}
+    pub fn is_dispersive(&self) -> bool{self.dispersion.is_some() || self.film.is_some()}
+    // Exact Fresnel reflectance
+    fn reflectance(cosine:f64, refraction_ratio:f64) -> f64{
+        fresnel_dielectric(cosine, 1.0 / refraction_ratio)
+    }
+    // Beer-Lambert transmittance over a distance inside the medium
+    fn transmittance(&self, distance:f64) -> Vec3{
+        vec3((-self.absorption.x * distance).exp(), (-self.absorption.y * distance).exp(), (-self.absorption.z * distance).exp())
+    }
+    pub fn scatter(&self, r_in:Ray3,rec:HitRecord) -> Option<ScatterResult>{
+        // Hitting the surface from inside means the ray just crossed the medium
+        let attenuation = if rec.front_face {Vec3::ones()} else {self.transmittance(rec.t * r_in.dir.length())};
+        let ir = self.ior_at(r_in.lambda);
+        let refraction_ratio = if rec.front_face {1.0 / ir} else {ir};
 
         let unit_direction = unit_vector(r_in.dir);
         let cos_theta = minf(dot(unit_direction * (-1.0), rec.normal), 1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta); 
+        let sin_theta = maxf(1.0 - cos_theta * cos_theta, 0.0).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let this_ray_reflects = Dielectric::reflectance(cos_theta, refraction_ratio) > random_f64_normalized();
-        let direction = if cannot_refract || this_ray_reflects {unit_direction.reflect(rec.normal)} else{refract(unit_direction, rec.normal, refraction_ratio)};
-        let scattered = Ray3::new(rec.p, direction);
-        Some(ScatterResult{attenuation:attenuation, scattered:scattered})
+        let reflect = Ray3::new(rec.p, unit_direction.reflect(rec.normal)).with_lambda(r_in.lambda);
+        let refracted = |a:Vec3| ScatterResult{attenuation:a, scattered:Ray3::new(rec.p, refract(unit_direction, rec.normal, refraction_ratio)).with_lambda(r_in.lambda)};
+        if cannot_refract {
+            return Some(ScatterResult{attenuation, scattered:reflect});
+        }
+        if let Some(film) = &self.film {
+            // The film sits on the outer surface, so from inside the medium is the incident side
+            let (n_inc, n_sub) = if rec.front_face {(1.0, ir)} else {(ir, 1.0)};
+            let r = film.reflectance(&rec, cos_theta, n_inc, Vec3::ones() * n_sub, Vec3::zeros(), r_in.lambda);
+            // Reflect with the mean reflectance and reweight the channels
+            let p = ((r.x + r.y + r.z) / 3.0).clamp(1e-4, 1.0 - 1e-4);
+            if random_f64_normalized() < p {
+                return Some(ScatterResult{attenuation:attenuation.mul_elements(r / p), scattered:reflect});
+            }
+            return Some(refracted(attenuation.mul_elements((Vec3::ones() - r) / (1.0 - p))));
+        }
+        if Dielectric::reflectance(cos_theta, refraction_ratio) > random_f64_normalized() {
+            Some(ScatterResult{attenuation, scattered:reflect})
+        } else {
+            Some(refracted(attenuation))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DiffuseLight{
+    emit : Texture
+}
+
+impl DiffuseLight{
+    pub fn new(emit:Texture) -> DiffuseLight{DiffuseLight{emit}}
+    fn emitted(&self, rec:&HitRecord) -> Vec3{
+        self.emit.value(rec.u, rec.v, rec.p)
     }
 }
 
 #[derive(Debug)]
 pub enum Material{
     Lambertian(Lambertian),
+    OrenNayar(OrenNayar),
     Metal(Metal),
-    Dielectric(Dielectric)
+    Dielectric(Dielectric),
+    DiffuseLight(DiffuseLight),
+    Conductor(Conductor),
+    RoughDielectric(RoughDielectric),
+    Principled(Box<Principled>),
+    // Material shaded with a perturbed normal
+    NormalMapped{base:Box<Material>, map:NormalMap},
+    // Material with an opacity mask read from the first channel.
+    // Intersection skips the transparent parts.
+    Masked{base:Box<Material>, alpha:Texture},
+    Mix(Mix),
+    Coated(Coated),
+    Subsurface(Subsurface),
+    // Tabulated BRDF, shared since the tables are large
+    Measured(Arc<MerlBrdf>),
+    // Phase function and emission of a Volume
+    Volume(VolumeMaterial),
+    Custom(Box<dyn Bsdf>)
 }
 
 impl Material{
-    pub fn mk_lambert(albedo:Vec3)->Material{Material::Lambertian(Lambertian{albedo:albedo})}
-    pub fn mk_metal(albedo:Vec3, fuzz:f64)->Material{Material::Metal(Metal::new(albedo, fuzz))}
+    pub fn mk_lambert(albedo:Vec3)->Material{Material::mk_lambert_tex(Texture::Constant(albedo))}
+    pub fn mk_lambert_tex(albedo:Texture)->Material{Material::Lambertian(Lambertian{albedo})}
+    pub fn mk_oren_nayar(albedo:Vec3, sigma:f64)->Material{Material::mk_oren_nayar_tex(Texture::Constant(albedo), sigma)}
+    pub fn mk_oren_nayar_tex(albedo:Texture, sigma:f64)->Material{Material::OrenNayar(OrenNayar::new(albedo, sigma))}
+    pub fn mk_metal(albedo:Vec3, fuzz:f64)->Material{Material::mk_metal_tex(Texture::Constant(albedo), fuzz)}
+    pub fn mk_metal_tex(albedo:Texture, fuzz:f64)->Material{Material::Metal(Metal::new(albedo, fuzz))}
     pub fn mk_dielectric(ir:f64)->Material{Material::Dielectric(Dielectric::new(ir))}
+    pub fn mk_dielectric_absorbing(ir:f64, color:Vec3, distance:f64)->Material{Material::Dielectric(Dielectric::with_absorption(ir, color, distance))}
+    pub fn mk_dielectric_dispersive(curve:IorCurve)->Material{Material::Dielectric(Dielectric::dispersive(curve))}
+    pub fn mk_metal_film(albedo:Vec3, fuzz:f64, film:ThinFilm)->Material{Material::Metal(Metal::new(Texture::Constant(albedo), fuzz).with_film(film))}
+    pub fn mk_dielectric_film(ir:f64, film:ThinFilm)->Material{Material::Dielectric(Dielectric::new(ir).with_film(film))}
+    pub fn mk_conductor(eta:Vec3, k:Vec3, roughness:f64)->Material{Material::Conductor(Conductor::new(eta, k, roughness))}
+    pub fn mk_anisotropic_conductor(eta:Vec3, k:Vec3, roughness_t:f64, roughness_b:f64, rotation:f64)->Material{
+        Material::Conductor(Conductor::anisotropic(eta, k, roughness_t, roughness_b, rotation))
+    }
+    pub fn mk_rough_dielectric(ir:f64, roughness:f64)->Material{Material::RoughDielectric(RoughDielectric::new(ir, roughness))}
+    pub fn mk_principled(p:Principled)->Material{Material::Principled(Box::new(p))}
+    pub fn with_normal_map(self, map:NormalMap)->Material{Material::NormalMapped{base:Box::new(self), map}}
+    pub fn with_alpha(self, alpha:Texture)->Material{Material::Masked{base:Box::new(self), alpha}}
+    pub(crate) fn mk_mix(a:MaterialId, b:MaterialId, weight:Texture)->Material{Material::Mix(Mix::new(a, b, weight))}
+    pub(crate) fn mk_coated(base:MaterialId, coat_ior:f64, coat_roughness:f64)->Material{Material::Coated(Coated::new(base, coat_ior, coat_roughness))}
+    pub fn mk_subsurface(ior:f64, albedo:Vec3, mfp:Vec3, g:f64)->Material{Material::Subsurface(Subsurface::new(ior, albedo, mfp, g))}
+    pub fn mk_measured(brdf:Arc<MerlBrdf>)->Material{Material::Measured(brdf)}
+    pub fn mk_volume(albedo:Vec3, g:f64)->Material{Material::Volume(VolumeMaterial::new(albedo, g))}
+    pub fn mk_emissive_volume(albedo:Vec3, g:f64, emission:Vec3, grid:Option<Arc<DensityGrid>>)->Material{
+        Material::Volume(VolumeMaterial::new(albedo, g).with_emission(emission, grid))
+    }
+    pub fn mk_custom(bsdf:impl Bsdf + 'static)->Material{Material::Custom(Box::new(bsdf))}
+    pub fn mk_diffuse_light(emit:Vec3)->Material{Material::mk_diffuse_light_tex(Texture::Constant(emit))}
+    pub fn mk_diffuse_light_tex(emit:Texture)->Material{Material::DiffuseLight(DiffuseLight::new(emit))}
+
+    // `mats` resolves the materials referenced by Mix and Coated
+    // scatter with the pdf of the sampled direction where samples_lights
+    pub fn sample(&self, r_in:Ray3, rec:HitRecord, mats:&MaterialCollection) -> Option<ScatterSample>{
+        let res = self.scatter(r_in, rec, mats)?;
+        let pdf = if self.samples_lights(mats) {
+            Some(self.pdf(unit_vector(r_in.dir) * -1.0, unit_vector(res.scattered.dir), &rec, mats))
+        } else {
+            None
+        };
+        Some(ScatterSample{attenuation:res.attenuation, scattered:res.scattered, pdf})
+    }
 
-    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) ->Option<ScatterResult>{
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord, mats:&MaterialCollection) ->Option<ScatterResult>{
         match self {
             Material::Lambertian(lamb) =>{
                 Some(lamb.scatter(rec))
@@ -336,13 +690,149 @@ impl Material{
             Material::Dielectric(dielectric) =>{
                 dielectric.scatter(r_in, rec)
             }
+            Material::DiffuseLight(_) => None,
+            Material::Conductor(conductor) => conductor.scatter(r_in, rec),
+            Material::RoughDielectric(dielectric) => dielectric.scatter(r_in, rec),
+            Material::Principled(principled) => principled.scatter(r_in, rec),
+            Material::NormalMapped{base, map} => base.scatter(r_in, map.perturb(&r_in, rec), mats),
+            Material::Masked{base, ..} => base.scatter(r_in, rec, mats),
+            Material::Mix(mix) => mats.materials[mix.pick(&rec)].scatter(r_in, rec, mats),
+            Material::Coated(coated) => coated.scatter(r_in, rec, mats),
+            Material::Subsurface(subsurface) => subsurface.scatter(r_in, rec),
+            Material::Measured(brdf) => brdf.scatter(r_in, rec),
+            Material::OrenNayar(oren_nayar) => oren_nayar.scatter(r_in, rec),
+            Material::Volume(volume) => volume.scatter(r_in, rec),
+            Material::Custom(bsdf) => bsdf.scatter(r_in, rec)
+        }
+    }
+
+    // Scattering depends on the wavelength, so spectral paths must keep only the hero wavelength
+    pub fn is_dispersive(&self, mats:&MaterialCollection) -> bool{
+        match self {
+            Material::Dielectric(dielectric) => dielectric.is_dispersive(),
+            Material::Metal(metal) => metal.film.is_some(),
+            Material::NormalMapped{base, ..} | Material::Masked{base, ..} => base.is_dispersive(mats),
+            Material::Mix(mix) => mats.materials[mix.a].is_dispersive(mats) || mats.materials[mix.b].is_dispersive(mats),
+            Material::Coated(coated) => mats.materials[coated.base].is_dispersive(mats),
+            _ => false
+        }
+    }
+
+    // Medium a path enters when it is transmitted through this material.
+    // Mixes have none, add_mix rejects components with a medium.
+    pub fn medium<'a>(&'a self, mats:&'a MaterialCollection) -> Option<&'a Subsurface>{
+        match self {
+            Material::Subsurface(subsurface) => Some(subsurface),
+            Material::NormalMapped{base, ..} | Material::Masked{base, ..} => base.medium(mats),
+            Material::Coated(coated) => mats.materials[coated.base].medium(mats),
             _ => None
         }
     }
+
+    pub fn opacity(&self, rec:&HitRecord, mats:&MaterialCollection) -> f64{
+        match self {
+            Material::Masked{base, alpha} => alpha.value(rec.u, rec.v, rec.p).x * base.opacity(rec, mats),
+            Material::NormalMapped{base, ..} => base.opacity(rec, mats),
+            Material::Mix(mix) => {
+                let w = mix.weight(rec);
+                mats.materials[mix.a].opacity(rec, mats) * (1.0 - w) + mats.materials[mix.b].opacity(rec, mats) * w
+            },
+            Material::Coated(coated) => mats.materials[coated.base].opacity(rec, mats),
+            _ => 1.0
+        }
+    }
+
+    pub fn emitted(&self, rec:&HitRecord, mats:&MaterialCollection) -> Vec3{
+        match self {
+            Material::DiffuseLight(light) => light.emitted(rec),
+            Material::Volume(volume) => volume.emitted(rec),
+            Material::Custom(bsdf) => bsdf.emitted(rec),
+            Material::NormalMapped{base, ..} | Material::Masked{base, ..} => base.emitted(rec, mats),
+            Material::Mix(mix) => {
+                let w = mix.weight(rec);
+                mats.materials[mix.a].emitted(rec, mats) * (1.0 - w) + mats.materials[mix.b].emitted(rec, mats) * w
+            },
+            // Emission from below passes through the coat
+            Material::Coated(coated) => mats.materials[coated.base].emitted(rec, mats),
+            _ => Vec3::zeros()
+        }
+    }
+
+    // Emission is sampled explicitly on spheres with this material
+    pub fn is_light(&self, mats:&MaterialCollection) -> bool{
+        match self {
+            Material::DiffuseLight(_) => true,
+            Material::NormalMapped{base, ..} => base.is_light(mats),
+            Material::Mix(mix) => mats.materials[mix.a].is_light(mats) || mats.materials[mix.b].is_light(mats),
+            Material::Coated(coated) => mats.materials[coated.base].is_light(mats),
+            _ => false
+        }
+    }
+
+    // Scattering is described by eval and pdf, so lights are sampled at hits
+    pub fn samples_lights(&self, mats:&MaterialCollection) -> bool{
+        match self {
+            Material::Lambertian(_) | Material::OrenNayar(_) | Material::Measured(_) => true,
+            // A mirror has no pdf
+            Material::Metal(metal) => metal.fuzz > 0.0,
+            Material::Custom(bsdf) => !bsdf.is_specular(),
+            Material::NormalMapped{base, ..} | Material::Masked{base, ..} => base.samples_lights(mats),
+            Material::Mix(mix) => mats.materials[mix.a].samples_lights(mats) && mats.materials[mix.b].samples_lights(mats),
+            _ => false
+        }
+    }
+
+    // BSDF times |cos| of wi for unit directions pointing away from the
+    // surface, wo towards the viewer. lambda is the wavelength of the path,
+    // as in Ray3. Only meaningful where samples_lights.
+    pub fn eval(&self, wo:Vec3, wi:Vec3, lambda:f64, rec:&HitRecord, mats:&MaterialCollection) -> Vec3{
+        match self {
+            Material::Lambertian(lamb) => lamb.eval(wi, rec),
+            Material::OrenNayar(oren_nayar) => oren_nayar.eval(wo, wi, rec),
+            Material::Metal(metal) => metal.eval(wo, wi, lambda, rec),
+            Material::Measured(brdf) => {
+                let frame = Onb::from_w(rec.normal);
+                let wi = frame.to_local(wi);
+                brdf.eval(frame.to_local(wo), wi) * wi.z.max(0.0)
+            },
+            Material::Custom(bsdf) => bsdf.eval(wo, wi, rec),
+            Material::NormalMapped{base, map} => base.eval(wo, wi, lambda, &map.perturb(&Ray3::new(rec.p, wo * -1.0), *rec), mats),
+            Material::Masked{base, ..} => base.eval(wo, wi, lambda, rec, mats),
+            Material::Mix(mix) => {
+                let w = mix.weight(rec);
+                mats.materials[mix.a].eval(wo, wi, lambda, rec, mats) * (1.0 - w) + mats.materials[mix.b].eval(wo, wi, lambda, rec, mats) * w
+            },
+            _ => Vec3::zeros()
+        }
+    }
+
+    // Solid angle density with which scatter picks wi, the same for every
+    // wavelength
+    pub fn pdf(&self, wo:Vec3, wi:Vec3, rec:&HitRecord, mats:&MaterialCollection) -> f64{
+        match self {
+            Material::Lambertian(_) | Material::OrenNayar(_) => dot(wi, rec.normal).max(0.0) / constants::PI_F64,
+            Material::Metal(metal) => metal.pdf((wo * -1.0).reflect(rec.normal), wi),
+            Material::Measured(brdf) => {
+                let frame = Onb::from_w(rec.normal);
+                brdf.pdf(frame.to_local(wo), frame.to_local(wi))
+            },
+            Material::Custom(bsdf) => bsdf.pdf(wo, wi, rec),
+            Material::NormalMapped{base, map} => base.pdf(wo, wi, &map.perturb(&Ray3::new(rec.p, wo * -1.0), *rec), mats),
+            Material::Masked{base, ..} => base.pdf(wo, wi, rec, mats),
+            Material::Mix(mix) => {
+                let w = mix.weight(rec);
+                mats.materials[mix.a].pdf(wo, wi, rec, mats) * (1.0 - w) + mats.materials[mix.b].pdf(wo, wi, rec, mats) * w
+            },
+            _ => 0.0
+        }
+    }
 }
 
+#[derive(Debug, Default)]
 pub struct MaterialCollection{
-    pub materials:Vec<Material>
+    // Only ever appended to, so ids stay valid and combinators keep
+    // referring to earlier materials
+    pub(crate) materials:Vec<Material>
 }
 
 pub type MaterialId = usize;
@@ -352,15 +842,88 @@ impl MaterialCollection{
         self.materials.push(mat);
         self.materials.len() - 1
     }
+    pub fn get(&self, id:MaterialId)->Option<&Material>{self.materials.get(id)}
     pub fn add_lambert(&mut self, col:Vec3)->MaterialId{
         self.add(Material::mk_lambert(col))
     }
+    pub fn add_lambert_tex(&mut self, albedo:Texture)->MaterialId{
+        self.add(Material::mk_lambert_tex(albedo))
+    }
     pub fn add_metal(&mut self, albedo:Vec3, fuzz:f64)->MaterialId{
         self.add(Material::mk_metal(albedo, fuzz))
     }
+    pub fn add_metal_tex(&mut self, albedo:Texture, fuzz:f64)->MaterialId{
+        self.add(Material::mk_metal_tex(albedo, fuzz))
+    }
+    pub fn add_conductor(&mut self, eta:Vec3, k:Vec3, roughness:f64)->MaterialId{
+        self.add(Material::mk_conductor(eta, k, roughness))
+    }
+    pub fn add_rough_dielectric(&mut self, ir:f64, roughness:f64)->MaterialId{
+        self.add(Material::mk_rough_dielectric(ir, roughness))
+    }
+    pub fn add_principled(&mut self, p:Principled)->MaterialId{
+        self.add(Material::mk_principled(p))
+    }
+    pub fn add_diffuse_light(&mut self, emit:Vec3)->MaterialId{
+        self.add(Material::mk_diffuse_light(emit))
+    }
     pub fn add_dielectric(&mut self, ir:f64)->MaterialId{
         self.add(Material::mk_dielectric(ir))
     }
+    pub fn add_dielectric_absorbing(&mut self, ir:f64, color:Vec3, distance:f64)->MaterialId{
+        self.add(Material::mk_dielectric_absorbing(ir, color, distance))
+    }
+    pub fn add_metal_film(&mut self, albedo:Vec3, fuzz:f64, film:ThinFilm)->MaterialId{
+        self.add(Material::mk_metal_film(albedo, fuzz, film))
+    }
+    pub fn add_dielectric_film(&mut self, ir:f64, film:ThinFilm)->MaterialId{
+        self.add(Material::mk_dielectric_film(ir, film))
+    }
+    pub fn add_subsurface(&mut self, ior:f64, albedo:Vec3, mfp:Vec3, g:f64)->MaterialId{
+        self.add(Material::mk_subsurface(ior, albedo, mfp, g))
+    }
+    pub fn add_anisotropic_conductor(&mut self, eta:Vec3, k:Vec3, roughness_t:f64, roughness_b:f64, rotation:f64)->MaterialId{
+        self.add(Material::mk_anisotropic_conductor(eta, k, roughness_t, roughness_b, rotation))
+    }
+    pub fn add_oren_nayar(&mut self, albedo:Vec3, sigma:f64)->MaterialId{
+        self.add(Material::mk_oren_nayar(albedo, sigma))
+    }
+    pub fn add_oren_nayar_tex(&mut self, albedo:Texture, sigma:f64)->MaterialId{
+        self.add(Material::mk_oren_nayar_tex(albedo, sigma))
+    }
+    pub fn add_volume(&mut self, albedo:Vec3, g:f64)->MaterialId{
+        self.add(Material::mk_volume(albedo, g))
+    }
+    pub fn add_emissive_volume(&mut self, albedo:Vec3, g:f64, emission:Vec3, grid:Option<Arc<DensityGrid>>)->MaterialId{
+        self.add(Material::mk_emissive_volume(albedo, g, emission, grid))
+    }
+    pub fn add_custom(&mut self, bsdf:impl Bsdf + 'static)->MaterialId{
+        self.add(Material::mk_custom(bsdf))
+    }
+    pub fn add_measured(&mut self, brdf:Arc<MerlBrdf>)->MaterialId{
+        self.add(Material::mk_measured(brdf))
+    }
+    // Mixes and coatings refer to materials already in the collection, which
+    // also keeps them from referring to themselves. None for an unknown id.
+    // A mix can not have a subsurface component either: the integrator starts
+    // a random walk from the material that was hit, not from the component
+    // the mix picked.
+    pub fn add_mix(&mut self, a:MaterialId, b:MaterialId, weight:Texture)->Option<MaterialId>{
+        let has_medium = |id:MaterialId| self.materials[id].medium(self).is_some();
+        if a >= self.materials.len() || b >= self.materials.len() || has_medium(a) || has_medium(b) {
+            return None;
+        }
+        Some(self.add(Material::mk_mix(a, b, weight)))
+    }
+    pub fn add_coated(&mut self, base:MaterialId, coat_ior:f64, coat_roughness:f64)->Option<MaterialId>{
+        if base >= self.materials.len() {
+            return None;
+        }
+        Some(self.add(Material::mk_coated(base, coat_ior, coat_roughness)))
+    }
+    pub fn add_dielectric_dispersive(&mut self, curve:IorCurve)->MaterialId{
+        self.add(Material::mk_dielectric_dispersive(curve))
+    }
 }
 
 // Hittable
@@ -371,11 +934,16 @@ pub struct HitRecord{
     pub normal : Vec3,
     pub mat : MaterialId,
     pub t : f64,
+    pub u : f64,
+    pub v : f64,
+    // Surface tangents along u and v, zero when the shape has no parameterization
+    pub dpdu : Vec3,
+    pub dpdv : Vec3,
     pub front_face : bool
 }
 impl HitRecord{
     pub fn new_default(mat:MaterialId)->HitRecord{
-        HitRecord{p:Vec3::zeros(), normal:Vec3::zeros(), mat:mat, t:0.0, front_face:false}
+        HitRecord{p:Vec3::zeros(), normal:Vec3::zeros(), mat:mat, t:0.0, u:0.0, v:0.0, dpdu:Vec3::zeros(), dpdv:Vec3::zeros(), front_face:false}
     }
     pub fn set_face_normal(&mut self, r:&Ray3, outward_normal:Vec3){
         self.front_face = dot(r.dir, outward_normal) < 0.0;
@@ -383,10 +951,74 @@ impl HitRecord{
     }
 }
 
+// Axis aligned bounding box
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb{
+    pub min : Vec3,
+    pub max : Vec3
+}
+
+impl Aabb{
+    pub fn new(min:Vec3, max:Vec3) -> Aabb{Aabb{min, max}}
+    pub fn empty() -> Aabb{
+        Aabb{min:vec3(f64::MAX, f64::MAX, f64::MAX), max:vec3(f64::MIN, f64::MIN, f64::MIN)}
+    }
+    pub fn is_empty(&self) -> bool{
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+    pub fn union(&self, b:&Aabb) -> Aabb{
+        Aabb{min:vmin(self.min, b.min), max:vmax(self.max, b.max)}
+    }
+    pub fn diagonal(&self) -> Vec3{self.max - self.min}
+    pub fn surface_area(&self) -> f64{
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+    pub fn longest_axis(&self) -> usize{
+        let d = self.diagonal();
+        if d.x > d.y && d.x > d.z {0} else if d.y > d.z {1} else {2}
+    }
+    // Slab test. Returns the parametric entry and exit distances clipped to [t_min, t_max].
+    pub fn hit(&self, r:&Ray3, t_min:f64, t_max:f64) -> Option<(f64, f64)>{
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for a in 0 .. 3 {
+            let inv_d = 1.0 / r.dir.axis(a);
+            let mut ta = (self.min.axis(a) - r.orig.axis(a)) * inv_d;
+            let mut tb = (self.max.axis(a) - r.orig.axis(a)) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut ta, &mut tb);
+            }
+            t0 = if ta > t0 {ta} else {t0};
+            t1 = if tb < t1 {tb} else {t1};
+            if t1 < t0 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
+
 pub trait HitRay{
+    // Surfaces masked out by their material (see SamplingCfg::masked) are not reported.
     fn hit(&self, r:&Ray3, cfg:SamplingCfg)  -> Option<HitRecord>;
 }
 
+// Acceleration structure built over a set of objects
+pub trait Accelerator : HitRay{
+    fn build(objs:Vec<HittableObject>) -> Self where Self:Sized;
+    fn bounding_box(&self) -> Aabb;
+    fn prims(&self) -> &[HittableObject];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccelKind{
+    List,
+    Grid,
+    KdTree
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct Sphere {
     pub center : Vec3,
     pub radius : f64,
@@ -405,6 +1037,30 @@ impl Sphere {
         let sr = castf64(r);
         Sphere{center:vec3(vx, vy,vz), radius:sr, material:mat}
     }
+
+    pub fn bounding_box(&self) -> Aabb{
+        let r = self.radius.abs();
+        let rv = vec3(r, r, r);
+        Aabb::new(self.center - rv, self.center + rv)
+    }
+
+    // Spherical coordinates of a point on the unit sphere. u runs around the
+    // y axis starting at -x, v from the south to the north pole.
+    pub fn uv(p:Vec3) -> (f64, f64){
+        let theta = f64::acos(clampf64(-p.y, -1.0, 1.0));
+        let phi = f64::atan2(-p.z, p.x) + constants::PI_F64;
+        (phi / (2.0 * constants::PI_F64), theta / constants::PI_F64)
+    }
+    // Partial derivatives of the point along the uv parameterization above
+    pub fn tangents(&self, p:Vec3) -> (Vec3, Vec3){
+        let d = p - self.center;
+        let r = self.radius.abs();
+        let n = d / r;
+        let sin_theta = maxf((1.0 - n.y * n.y).max(0.0).sqrt(), 1e-8);
+        let dpdu = vec3(d.z, 0.0, -d.x) * (2.0 * constants::PI_F64);
+        let dpdv = vec3(-n.x * n.y / sin_theta, sin_theta, -n.y * n.z / sin_theta) * (constants::PI_F64 * r);
+        (dpdu, dpdv)
+    }
 }
 
 impl HitRay for Sphere {
@@ -422,28 +1078,35 @@ impl HitRay for Sphere {
 
         let sqrtd = discrm.sqrt();
         let rootmin = (-half_b - sqrtd) / a;
-        let mut root = rootmin;
-        if ! cfg.inrange(rootmin) {
-            let rootmax = (-half_b + sqrtd) / a;
-            root = rootmax;
-            if ! cfg.inrange(rootmax){
-                return None;
+        let rootmax = (-half_b + sqrtd) / a;
+        // The far root is tried when the near one is out of range or cut out
+        for root in [rootmin, rootmax] {
+            if ! cfg.inrange(root) {
+                continue;
+            }
+            let mut record : HitRecord = HitRecord::new_default(self.material);
+            record.t = root;
+            record.p = r.at(record.t);
+            let outward_normal = (record.p - self.center) / self.radius;
+            record.set_face_normal(r, outward_normal);
+            (record.u, record.v) = Sphere::uv((record.p - self.center) / self.radius.abs());
+            (record.dpdu, record.dpdv) = self.tangents(record.p);
+            if cfg.masked(r, &record) {
+                continue;
             }
+            return Some(record);
         }
-
-        let mut record : HitRecord = HitRecord::new_default(self.material);
-        record.t = root;
-        record.p = r.at(record.t);
-        let outward_normal = (record.p - self.center) / self.radius;
-        record.set_face_normal(r, outward_normal);
-        Some(record)
+        None
     }
 }
 
 // hittable list
 pub enum HittableObject{
     Sphere(Sphere),
-    List(Vec<HittableObject>)
+    List(Vec<HittableObject>),
+    Grid(UniformGrid),
+    KdTree(KdTree),
+    Volume(Volume)
 }
 
 impl HittableObject{
@@ -451,6 +1114,31 @@ impl HittableObject{
     pub fn wrap(v:Vec<HittableObject>) -> HittableObject{
         HittableObject::List(v)
     }
+    pub fn wrap_accel(kind:AccelKind, v:Vec<HittableObject>) -> HittableObject{
+        match kind {
+            AccelKind::List => HittableObject::List(v),
+            AccelKind::Grid => HittableObject::Grid(UniformGrid::build(v)),
+            AccelKind::KdTree => HittableObject::KdTree(KdTree::build(v))
+        }
+    }
+    pub fn bounding_box(&self) -> Aabb{
+        match self {
+            HittableObject::Sphere(sphere) => sphere.bounding_box(),
+            HittableObject::List(objs) => objs.iter().fold(Aabb::empty(), |b, o| b.union(&o.bounding_box())),
+            HittableObject::Grid(grid) => grid.bounding_box(),
+            HittableObject::KdTree(tree) => tree.bounding_box(),
+            HittableObject::Volume(volume) => volume.bounding_box()
+        }
+    }
+    // Objects contained in a list or accelerator
+    pub fn children(&self) -> &[HittableObject]{
+        match self {
+            HittableObject::List(objs) => objs,
+            HittableObject::Grid(grid) => grid.prims(),
+            HittableObject::KdTree(tree) => tree.prims(),
+            HittableObject::Sphere(_) | HittableObject::Volume(_) => &[]
+        }
+    }
 }
 
 impl HitRay for HittableObject{
@@ -471,6 +1159,9 @@ impl HitRay for HittableObject{
                 }
                 res
             }
+            HittableObject::Grid(grid) => grid.hit(r, cfg),
+            HittableObject::KdTree(tree) => tree.hit(r, cfg),
+            HittableObject::Volume(volume) => volume.hit(r, cfg)
         }
     }
 }
@@ -478,9 +1169,65 @@ impl HitRay for HittableObject{
 pub fn mk_sphere(x:f64, y:f64, z:f64, r:f64, mat:MaterialId)->HittableObject{
     HittableObject::Sphere(Sphere{center:vec3(x,y,z), radius:r, material:mat})
 }
+pub fn mk_volume(density:Arc<DensityGrid>, sigma_t:f64, mat:MaterialId)->HittableObject{
+    HittableObject::Volume(Volume::new(density, sigma_t, mat))
+}
 pub fn mk_sphere2(center:Vec3, r:f64, mat:MaterialId)->HittableObject{
     HittableObject::Sphere(Sphere{center:center, radius:r, material:mat})
 }
+
+// Scene
+// Radiance arriving along rays that leave the scene
+#[derive(Debug, Copy, Clone)]
+pub enum Background{
+    Sky,
+    Solid(Vec3)
+}
+
+impl Background{
+    pub fn value(&self, r:&Ray3) -> Vec3{
+        match self {
+            Background::Sky => {
+                let udir = unit_vector(r.direction());
+                let t = 0.5 * (udir.y + 1.0);
+                lerp3(vec3(1.0,1.0,1.0), vec3(0.5, 0.7, 1.0), t)
+            },
+            Background::Solid(col) => *col
+        }
+    }
+}
+
+pub struct Scene{
+    pub world : HittableObject,
+    pub mats : MaterialCollection,
+    pub background : Background,
+    pub lights : Vec<Light>
+}
+
+impl Scene{
+    pub fn new(world:HittableObject, mats:MaterialCollection) -> Scene{
+        let mut lights = vec![];
+        collect_lights(&world, &mats, &mut lights);
+        Scene{world, mats, background:Background::Sky, lights}
+    }
+    // Density with which light sampling from `origin` picks the unit
+    // direction wi that ends at `rec`, zero if rec is not on a light
+    pub fn light_pdf(&self, origin:Vec3, wi:Vec3, rec:&HitRecord) -> f64{
+        match self.lights.iter().find(|l| l.contains(rec)) {
+            Some(light) => light.pdf(origin, wi) / self.lights.len() as f64,
+            None => 0.0
+        }
+    }
+    pub fn with_background(mut self, background:Background) -> Scene{
+        self.background = background;
+        self
+    }
+    // Light without geometry, such as Light::point
+    pub fn with_light(mut self, light:Light) -> Scene{
+        self.lights.push(light);
+        self
+    }
+}
 // Color
 
 use Vec3 as ColorRGB;
@@ -664,4 +1411,100 @@ impl Camera {
         let raydir = self.lower_left_corner + (self.horizontal * s) + (self.vertical * t) - self.origin - offset;
         Ray3::new(self.origin + offset,raydir)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn sphere_roots_are_masked_independently(){
+        let mut mats = MaterialCollection::new();
+        let ghost = mats.add(Material::mk_lambert(vec3(0.5, 0.5, 0.5)).with_alpha(Texture::Constant(Vec3::ones() * 0.5)));
+        let sphere = Sphere::new(Vec3::zeros(), 1.0, ghost);
+        let cfg = SamplingCfg::new(0.001, constants::INFINITY_F64).with_masks(&mats);
+        let n = 4000;
+        let mut misses = 0;
+        for i in 0 .. n {
+            let r = Ray3::new(vec3(i as f64 * 1e-4, 0.0, 5.0), vec3(0.0, 0.0, -1.0));
+            let hit = sphere.hit(&r, cfg);
+            // The same ray always gives the same answer
+            assert_eq!(hit.map(|h| h.t), sphere.hit(&r, cfg).map(|h| h.t));
+            if hit.is_none() {
+                misses += 1;
+            }
+        }
+        // Both sides cut out a quarter of the time, not half
+        let rate = misses as f64 / n as f64;
+        assert!((rate - 0.25).abs() < 0.04, "miss rate {}", rate);
+    }
+
+    #[test]
+    fn oren_nayar_scatters_above_the_surface(){
+        let mats = MaterialCollection::new();
+        let m = Material::mk_oren_nayar_tex(Texture::Constant(vec3(0.5, 0.5, 0.5)), 20.0);
+        let rec = HitRecord{normal:vec3(0.0, 1.0, 0.0), t:1.0, front_face:true, ..HitRecord::new_default(0)};
+        let r_in = Ray3::new(vec3(0.0, 1.0, 1.0), vec3(0.0, -1.0, -1.0));
+        for _ in 0 .. 1000 {
+            if let Some(s) = m.scatter(r_in, rec, &mats) {
+                let d = unit_vector(s.scattered.dir);
+                assert!(d.y > 0.0 && s.attenuation.x.is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn light_and_bsdf_samples_agree_for_film_metals(){
+        let mut mats = MaterialCollection::new();
+        let id = mats.add_metal_film(vec3(0.54, 0.5, 0.45), 0.3, ThinFilm::new(350.0, 2.4));
+        let m = &mats.materials[id];
+        let rec = HitRecord{normal:vec3(0.0, 1.0, 0.0), t:1.0, front_face:true, ..HitRecord::new_default(0)};
+        let lambda = 480.0;
+        let r_in = Ray3::new(vec3(-1.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0)).with_lambda(lambda);
+        let wo = unit_vector(r_in.dir) * -1.0;
+        let mut n = 0;
+        while n < 100 {
+            let s = match m.sample(r_in, rec, &mats) {
+                Some(s) => s,
+                None => continue
+            };
+            // The light sampling estimate f / pdf of the direction the BSDF picked
+            let wi = unit_vector(s.scattered.dir);
+            let pdf = s.pdf.unwrap();
+            let light_estimate = m.eval(wo, wi, lambda, &rec, &mats) / pdf;
+            assert!((light_estimate - s.attenuation).length() < 1e-9 * s.attenuation.length(), "{:?} {:?}", light_estimate, s.attenuation);
+            assert_eq!(s.attenuation.x, s.attenuation.z);
+            n += 1;
+        }
+    }
+
+    #[test]
+    fn mix_and_coating_of_existing_materials(){
+        let mut mats = MaterialCollection::new();
+        let a = mats.add(Material::mk_lambert(vec3(0.8, 0.1, 0.1)));
+        let b = mats.add(Material::mk_metal(vec3(0.9, 0.9, 0.9), 0.0));
+        let mix = mats.add_mix(a, b, Texture::Constant(Vec3::ones() * 0.5)).unwrap();
+        assert_eq!(mats.add_coated(mix, 1.5, 0.1), Some(3));
+    }
+
+    #[test]
+    fn mix_and_coating_of_unknown_materials_are_rejected(){
+        let mut mats = MaterialCollection::new();
+        let a = mats.add(Material::mk_lambert(vec3(0.8, 0.1, 0.1)));
+        // The mix itself would get id 1
+        assert_eq!(mats.add_mix(a, 1, Texture::Constant(Vec3::ones() * 0.5)), None);
+        assert_eq!(mats.add_coated(1, 1.5, 0.1), None);
+        assert_eq!(mats.materials.len(), 1);
+    }
+
+    #[test]
+    fn mix_with_a_subsurface_component_is_rejected(){
+        let mut mats = MaterialCollection::new();
+        let wax = mats.add_subsurface(1.4, vec3(0.9, 0.8, 0.6), vec3(0.5, 0.5, 0.5), 0.0);
+        let coated_wax = mats.add_coated(wax, 1.5, 0.1).unwrap();
+        let paint = mats.add_lambert(vec3(0.2, 0.3, 0.8));
+        let weight = || Texture::Constant(Vec3::ones() * 0.5);
+        assert_eq!(mats.add_mix(paint, wax, weight()), None);
+        assert_eq!(mats.add_mix(coated_wax, paint, weight()), None);
+        assert!(mats.add_mix(paint, paint, weight()).is_some());
+    }
+}
@@ -0,0 +1,247 @@
+use std::ops::{Add, Mul};
+use std::sync::OnceLock;
+use crate::raymath::{Vec3, vec3};
+
+// Spectral rendering
+pub const LAMBDA_MIN : f64 = 380.0;
+pub const LAMBDA_MAX : f64 = 780.0;
+pub const N_SAMPLES : usize = 4;
+// Wavelength used for the single index of refraction in RGB mode (sodium D line)
+pub const LAMBDA_D : f64 = 589.3;
+
+// Path weights carried per wavelength. In RGB mode the first three entries
+// are the red, green and blue channels and the last one is unused.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Spectrum{
+    pub c : [f64; N_SAMPLES]
+}
+
+impl Spectrum{
+    pub fn zeros() -> Spectrum{Spectrum{c:[0.0; N_SAMPLES]}}
+    pub fn ones() -> Spectrum{Spectrum{c:[1.0; N_SAMPLES]}}
+    pub fn from_rgb(rgb:Vec3) -> Spectrum{Spectrum{c:[rgb.x, rgb.y, rgb.z, 0.0]}}
+    pub fn rgb(&self) -> Vec3{vec3(self.c[0], self.c[1], self.c[2])}
+    pub fn max_value(&self) -> f64{self.c.iter().fold(0.0, |m, &v| if v > m {v} else {m})}
+}
+
+impl Add for Spectrum{
+    type Output = Spectrum;
+    fn add(self, o:Spectrum) -> Spectrum{
+        let mut c = self.c;
+        for (a, b) in c.iter_mut().zip(o.c.iter()) {
+            *a += b;
+        }
+        Spectrum{c}
+    }
+}
+
+impl Mul for Spectrum{
+    type Output = Spectrum;
+    fn mul(self, o:Spectrum) -> Spectrum{
+        let mut c = self.c;
+        for (a, b) in c.iter_mut().zip(o.c.iter()) {
+            *a *= b;
+        }
+        Spectrum{c}
+    }
+}
+
+impl Mul<f64> for Spectrum{
+    type Output = Spectrum;
+    fn mul(self, t:f64) -> Spectrum{
+        let mut c = self.c;
+        for a in c.iter_mut() {
+            *a *= t;
+        }
+        Spectrum{c}
+    }
+}
+
+// Hero wavelength sampling: one uniformly sampled wavelength plus N_SAMPLES - 1
+// equally spaced companions that share the path.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SampledWavelengths{
+    pub lambda : [f64; N_SAMPLES],
+    pub pdf : [f64; N_SAMPLES]
+}
+
+impl SampledWavelengths{
+    pub fn sample_uniform(u:f64) -> SampledWavelengths{
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let mut lambda = [0.0; N_SAMPLES];
+        lambda[0] = LAMBDA_MIN + u * range;
+        let delta = range / N_SAMPLES as f64;
+        for i in 1 .. N_SAMPLES {
+            lambda[i] = lambda[i - 1] + delta;
+            if lambda[i] > LAMBDA_MAX {
+                lambda[i] -= range;
+            }
+        }
+        SampledWavelengths{lambda, pdf:[1.0 / range; N_SAMPLES]}
+    }
+
+    pub fn hero(&self) -> f64{self.lambda[0]}
+
+    // After a wavelength dependent scattering event only the hero wavelength
+    // keeps a valid path.
+    pub fn terminate_secondary(&mut self){
+        if self.secondary_terminated() {
+            return;
+        }
+        for i in 1 .. N_SAMPLES {
+            self.pdf[i] = 0.0;
+        }
+        self.pdf[0] /= N_SAMPLES as f64;
+    }
+
+    pub fn secondary_terminated(&self) -> bool{
+        self.pdf[1 ..].iter().all(|&p| p == 0.0)
+    }
+
+    // Uplift an RGB reflectance or radiance to these wavelengths
+    pub fn uplift(&self, rgb:Vec3) -> Spectrum{
+        let mut c = [0.0; N_SAMPLES];
+        for (v, &l) in c.iter_mut().zip(self.lambda.iter()) {
+            *v = rgb_to_spectrum(rgb, l);
+        }
+        Spectrum{c}
+    }
+
+    // Monte Carlo estimate of the linear sRGB color of a spectral sample
+    pub fn rgb(&self, s:&Spectrum) -> Vec3{
+        let mut xyz = Vec3::zeros();
+        for i in 0 .. N_SAMPLES {
+            if self.pdf[i] != 0.0 {
+                xyz = xyz + cie_xyz(self.lambda[i]) * (s.c[i] / self.pdf[i]);
+            }
+        }
+        let xyz = xyz / (N_SAMPLES as f64 * cie_y_integral());
+        let rgb = xyz_to_rgb(xyz);
+        let white = flat_white_rgb();
+        vec3((rgb.x / white.x).max(0.0), (rgb.y / white.y).max(0.0), (rgb.z / white.z).max(0.0))
+    }
+}
+
+// Smits 1999, "An RGB to Spectrum Conversion for Reflectances"
+// Ten bins spanning 380 to 720 nm.
+const SMITS_WHITE : [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN : [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA : [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW : [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED : [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN : [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE : [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+const SMITS_MIN : f64 = 380.0;
+const SMITS_MAX : f64 = 720.0;
+
+// Linear interpolation between bin centers
+fn smits_basis(table:&[f64; 10], lambda:f64) -> f64{
+    let bin = (SMITS_MAX - SMITS_MIN) / 10.0;
+    let x = ((lambda - SMITS_MIN) / bin - 0.5).clamp(0.0, 9.0);
+    let i = (x as usize).min(8);
+    let t = x - i as f64;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+pub fn rgb_to_spectrum(rgb:Vec3, lambda:f64) -> f64{
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let basis = |table:&[f64; 10]| smits_basis(table, lambda);
+    if r <= g && r <= b {
+        let s = r * basis(&SMITS_WHITE);
+        if g <= b {
+            s + (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            s + (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        let s = g * basis(&SMITS_WHITE);
+        if r <= b {
+            s + (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            s + (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else {
+        let s = b * basis(&SMITS_WHITE);
+        if r <= g {
+            s + (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+        } else {
+            s + (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+        }
+    }
+}
+
+// Wyman, Sloan and Shirley 2013, multi-lobe fit of the CIE 1931 color matching functions
+fn piecewise_gaussian(x:f64, mu:f64, s1:f64, s2:f64) -> f64{
+    let s = if x < mu {s1} else {s2};
+    let t = (x - mu) / s;
+    (-0.5 * t * t).exp()
+}
+
+pub fn cie_xyz(lambda:f64) -> Vec3{
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+    vec3(x, y, z)
+}
+
+// Integral of the color matching functions over the sampled range
+fn cie_integral() -> Vec3{
+    static INTEGRAL : OnceLock<Vec3> = OnceLock::new();
+    *INTEGRAL.get_or_init(|| {
+        let steps = 4000;
+        let dl = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+        (0 .. steps).fold(Vec3::zeros(), |acc, i| acc + cie_xyz(LAMBDA_MIN + (i as f64 + 0.5) * dl) * dl)
+    })
+}
+
+fn cie_y_integral() -> f64{cie_integral().y}
+
+pub fn xyz_to_rgb(xyz:Vec3) -> Vec3{
+    vec3(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z)
+}
+
+// sRGB of a constant unit spectrum, used to white balance so that a flat
+// spectrum maps back to (1, 1, 1).
+fn flat_white_rgb() -> Vec3{
+    xyz_to_rgb(cie_integral() / cie_y_integral())
+}
+
+// Index of refraction as a function of wavelength in nm
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IorCurve{
+    // n = a + b / lambda^2, lambda in micrometers
+    Cauchy{a:f64, b:f64},
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i), lambda in micrometers
+    Sellmeier{b:[f64; 3], c:[f64; 3]}
+}
+
+impl IorCurve{
+    pub fn bk7() -> IorCurve{
+        IorCurve::Sellmeier{b:[1.03961212, 0.231792344, 1.01046945], c:[0.00600069867, 0.0200179144, 103.560653]}
+    }
+    pub fn sf11() -> IorCurve{
+        IorCurve::Sellmeier{b:[1.73759695, 0.313747346, 1.89878101], c:[0.013188707, 0.0623068142, 155.23629]}
+    }
+    pub fn diamond() -> IorCurve{
+        IorCurve::Sellmeier{b:[0.3306, 4.3356, 0.0], c:[0.030625, 0.011236, 0.0]}
+    }
+
+    pub fn eval(&self, lambda:f64) -> f64{
+        let um = lambda * 1e-3;
+        let l2 = um * um;
+        match self {
+            IorCurve::Cauchy{a, b} => a + b / l2,
+            IorCurve::Sellmeier{b, c} => {
+                let n2 = 1.0 + (0 .. 3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>();
+                n2.sqrt()
+            }
+        }
+    }
+}
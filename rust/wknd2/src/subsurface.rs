@@ -0,0 +1,62 @@
+use crate::raymath::{Vec3, vec3, unit_vector, Onb, Ray3, HitRecord, ScatterResult, Dielectric, random_f64_normalized, constants};
+
+// Random walk subsurface scattering
+// The surface is a smooth dielectric boundary. Paths that refract inside
+// walk through a homogeneous medium (see PathState::shade) until they leave
+// through the boundary again.
+#[derive(Debug)]
+pub struct Subsurface{
+    boundary : Dielectric,
+    // Extinction and scattering coefficients per unit distance
+    pub sigma_t : Vec3,
+    pub sigma_s : Vec3,
+    // Henyey-Greenstein anisotropy of the phase function
+    pub g : f64
+}
+
+// Single scattering albedo that gives a multiple scattering albedo of `a`
+// for a semi-infinite slab (the inversion used by Cycles' random walk).
+fn single_scattering_albedo(a:f64) -> f64{
+    let a = a.clamp(0.0, 0.999);
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    1.0 - s * s
+}
+
+impl Subsurface{
+    // `albedo` is the overall color of the material, `mfp` the mean free
+    // path per channel, both per RGB channel.
+    pub fn new(ior:f64, albedo:Vec3, mfp:Vec3, g:f64) -> Subsurface{
+        let sigma_t = vec3(1.0 / mfp.x.max(1e-6), 1.0 / mfp.y.max(1e-6), 1.0 / mfp.z.max(1e-6));
+        let alpha = vec3(single_scattering_albedo(albedo.x), single_scattering_albedo(albedo.y), single_scattering_albedo(albedo.z));
+        Subsurface{boundary:Dielectric::new(ior), sigma_t, sigma_s:sigma_t.mul_elements(alpha), g}
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        self.boundary.scatter(r_in, rec)
+    }
+}
+
+// Sample a direction from the Henyey-Greenstein phase function around the
+// propagation direction `dir`. The phase function is the sampling density, so
+// the weight is one.
+pub fn sample_hg(dir:Vec3, g:f64) -> Vec3{
+    let u1 = random_f64_normalized();
+    let u2 = random_f64_normalized();
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let sq = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+        ((1.0 + g * g - sq * sq) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * constants::PI_F64 * u2;
+    Onb::from_w(unit_vector(dir)).to_world(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+}
+
+// Henyey-Greenstein phase function value for the angle between the
+// propagation directions before and after scattering
+pub fn hg_phase(cos_theta:f64, g:f64) -> f64{
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * constants::PI_F64 * denom * denom.max(1e-12).sqrt())
+}
+
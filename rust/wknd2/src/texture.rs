@@ -0,0 +1,122 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::raymath::{Vec3, vec3, lerp3};
+use crate::noise::{Perlin, NoisePattern, pattern_value};
+
+// Textures
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WrapMode{
+    Repeat,
+    Clamp
+}
+
+pub struct ImageTexture{
+    pub width : usize,
+    pub height : usize,
+    pub wrap : WrapMode,
+    texels : Vec<Vec3>
+}
+
+impl fmt::Debug for ImageTexture{
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result{
+        write!(f, "ImageTexture {{ width: {}, height: {}, wrap: {:?} }}", self.width, self.height, self.wrap)
+    }
+}
+
+impl ImageTexture{
+    // 8 bit texels are decoded with the same gamma 2 that write_color_to_buf
+    // encodes with, so an image round trips through a white diffuse surface.
+    pub fn load(path:&str, wrap:WrapMode) -> Result<ImageTexture, image::ImageError>{
+        ImageTexture::load_with(path, wrap, |f| f * f)
+    }
+
+    // Data textures such as normal maps are stored without gamma.
+    pub fn load_linear(path:&str, wrap:WrapMode) -> Result<ImageTexture, image::ImageError>{
+        ImageTexture::load_with(path, wrap, |f| f)
+    }
+
+    fn load_with(path:&str, wrap:WrapMode, decode:fn(f64) -> f64) -> Result<ImageTexture, image::ImageError>{
+        let img = image::open(path)?.to_rgb8();
+        let (w, h) = img.dimensions();
+        let texel = |c:u8| decode(c as f64 / 255.0);
+        let texels = img.pixels().map(|px| vec3(texel(px[0]), texel(px[1]), texel(px[2]))).collect();
+        Ok(ImageTexture{width:w as usize, height:h as usize, wrap, texels})
+    }
+
+    pub fn from_texels(width:usize, height:usize, wrap:WrapMode, texels:Vec<Vec3>) -> ImageTexture{
+        assert_eq!(texels.len(), width * height);
+        ImageTexture{width, height, wrap, texels}
+    }
+
+    fn coord(&self, i:i64, n:usize) -> usize{
+        match self.wrap {
+            WrapMode::Repeat => i.rem_euclid(n as i64) as usize,
+            WrapMode::Clamp => i.clamp(0, n as i64 - 1) as usize
+        }
+    }
+
+    fn texel(&self, x:i64, y:i64) -> Vec3{
+        self.texels[self.coord(y, self.height) * self.width + self.coord(x, self.width)]
+    }
+
+    // Bilinear lookup. v = 0 is the bottom row of the image.
+    pub fn sample(&self, u:f64, v:f64) -> Vec3{
+        if self.texels.is_empty() {
+            return vec3(0.0, 1.0, 1.0);
+        }
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Texture{
+    Constant(Vec3),
+    // 3D checker with cells of size `scale`
+    Checker{scale:f64, odd:Box<Texture>, even:Box<Texture>},
+    Image(Arc<ImageTexture>),
+    // Procedural pattern blending from `a` to `b`
+    Noise{noise:Arc<Perlin>, pattern:NoisePattern, scale:f64, a:Vec3, b:Vec3}
+}
+
+impl Texture{
+    pub fn constant(col:Vec3) -> Texture{Texture::Constant(col)}
+    pub fn checker(scale:f64, odd:Vec3, even:Vec3) -> Texture{
+        Texture::Checker{scale, odd:Box::new(Texture::Constant(odd)), even:Box::new(Texture::Constant(even))}
+    }
+    pub fn image(img:Arc<ImageTexture>) -> Texture{Texture::Image(img)}
+    pub fn noise(seed:u64, pattern:NoisePattern, scale:f64, a:Vec3, b:Vec3) -> Texture{
+        Texture::Noise{noise:Arc::new(Perlin::new(seed)), pattern, scale, a, b}
+    }
+    pub fn load_image(path:&str, wrap:WrapMode) -> Result<Texture, image::ImageError>{
+        Ok(Texture::Image(Arc::new(ImageTexture::load(path, wrap)?)))
+    }
+    pub fn load_image_linear(path:&str, wrap:WrapMode) -> Result<Texture, image::ImageError>{
+        Ok(Texture::Image(Arc::new(ImageTexture::load_linear(path, wrap)?)))
+    }
+
+    pub fn value(&self, u:f64, v:f64, p:Vec3) -> Vec3{
+        match self {
+            Texture::Constant(col) => *col,
+            Texture::Checker{scale, odd, even} => {
+                let inv = 1.0 / scale;
+                let sum = (p.x * inv).floor() as i64 + (p.y * inv).floor() as i64 + (p.z * inv).floor() as i64;
+                if sum % 2 == 0 {even.value(u, v, p)} else {odd.value(u, v, p)}
+            },
+            Texture::Image(img) => img.sample(u, v),
+            Texture::Noise{noise, pattern, scale, a, b} => lerp3(*a, *b, pattern_value(noise, *pattern, *scale, p))
+        }
+    }
+}
+
+impl From<Vec3> for Texture{
+    fn from(col:Vec3) -> Texture{Texture::Constant(col)}
+}
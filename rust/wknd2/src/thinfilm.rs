@@ -0,0 +1,101 @@
+use num::complex::Complex64;
+use crate::raymath::{Vec3, vec3, HitRecord, constants};
+use crate::texture::Texture;
+
+// Thin-film interference coating
+// A single dielectric layer over a substrate. Reflectance is the Airy sum of
+// the waves bouncing inside the film, averaged over s and p polarization.
+
+// Wavelengths in nm the RGB channels are evaluated at, matching microfacet::metals
+pub const RGB_WAVELENGTHS : [f64; 3] = [650.0, 550.0, 450.0];
+
+#[derive(Debug, Clone)]
+pub struct ThinFilm{
+    // Film thickness in nm, read from the first channel
+    pub thickness : Texture,
+    // Film index of refraction, read from the first channel
+    pub ior : Texture
+}
+
+// Amplitude reflection coefficients (s, p) at an interface between n_i and n_t
+fn fresnel_amplitudes(n_i:Complex64, cos_i:Complex64, n_t:Complex64, cos_t:Complex64) -> (Complex64, Complex64){
+    let rs = (n_i * cos_i - n_t * cos_t) / (n_i * cos_i + n_t * cos_t);
+    let rp = (n_t * cos_i - n_i * cos_t) / (n_t * cos_i + n_i * cos_t);
+    (rs, rp)
+}
+
+// Cosine of the refracted angle from Snell's law, complex for absorbing media
+fn snell_cos(n_i:f64, sin2_i:f64, n_t:Complex64) -> Complex64{
+    let one = Complex64::new(1.0, 0.0);
+    let sin2_t = Complex64::new(n_i * n_i * sin2_i, 0.0) / (n_t * n_t);
+    let cos_t = (one - sin2_t).sqrt();
+    // Pick the root decaying into the medium
+    if cos_t.re < 0.0 || (cos_t.re == 0.0 && cos_t.im < 0.0) {-cos_t} else {cos_t}
+}
+
+// Reflectance of a film of index n2 and thickness d (nm) between an incident
+// medium n1 and a substrate with complex index n3, at wavelength lambda (nm).
+pub fn airy_reflectance(cos_i:f64, n1:f64, n2:f64, n3:Complex64, d:f64, lambda:f64) -> f64{
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_i = 1.0 - cos_i * cos_i;
+    let n1c = Complex64::new(n1, 0.0);
+    let n2c = Complex64::new(n2, 0.0);
+    let c1 = Complex64::new(cos_i, 0.0);
+    let c2 = snell_cos(n1, sin2_i, n2c);
+    let c3 = snell_cos(n1, sin2_i, n3);
+    let (r12s, r12p) = fresnel_amplitudes(n1c, c1, n2c, c2);
+    let (r23s, r23p) = fresnel_amplitudes(n2c, c2, n3, c3);
+    // Phase picked up by one round trip through the film
+    let delta = n2c * c2 * (2.0 * constants::PI_F64 * d / lambda);
+    let phase = (Complex64::new(0.0, 2.0) * delta).exp();
+    let airy = |r12:Complex64, r23:Complex64| {
+        let r = (r12 + r23 * phase) / (Complex64::new(1.0, 0.0) + r12 * r23 * phase);
+        r.norm_sqr()
+    };
+    (0.5 * (airy(r12s, r23s) + airy(r12p, r23p))).clamp(0.0, 1.0)
+}
+
+// Value of an RGB quantity at a wavelength, interpolating between RGB_WAVELENGTHS
+fn rgb_at(v:Vec3, lambda:f64) -> f64{
+    if lambda >= RGB_WAVELENGTHS[1] {
+        let t = ((lambda - RGB_WAVELENGTHS[1]) / (RGB_WAVELENGTHS[0] - RGB_WAVELENGTHS[1])).min(1.0);
+        v.y + (v.x - v.y) * t
+    } else {
+        let t = ((RGB_WAVELENGTHS[1] - lambda) / (RGB_WAVELENGTHS[1] - RGB_WAVELENGTHS[2])).min(1.0);
+        v.y + (v.z - v.y) * t
+    }
+}
+
+// Gulbrandsen 2014, "Artist Friendly Metallic Fresnel". Complex index (eta, k)
+// of a conductor with normal reflectance r and edge tint g.
+pub fn conductor_ior(r:Vec3, g:Vec3) -> (Vec3, Vec3){
+    let channel = |r:f64, g:f64| {
+        let r = r.clamp(0.0, 0.99);
+        let sr = r.sqrt();
+        let n = g * (1.0 - r) / (1.0 + r) + (1.0 - g) * (1.0 + sr) / (1.0 - sr);
+        let k2 = (r * (n + 1.0) * (n + 1.0) - (n - 1.0) * (n - 1.0)) / (1.0 - r);
+        (n, k2.max(0.0).sqrt())
+    };
+    let (x, y, z) = (channel(r.x, g.x), channel(r.y, g.y), channel(r.z, g.z));
+    (vec3(x.0, y.0, z.0), vec3(x.1, y.1, z.1))
+}
+
+impl ThinFilm{
+    pub fn new(thickness:f64, ior:f64) -> ThinFilm{
+        ThinFilm{thickness:Texture::constant(Vec3::ones() * thickness), ior:Texture::constant(Vec3::ones() * ior)}
+    }
+    pub fn with_textures(thickness:Texture, ior:Texture) -> ThinFilm{ThinFilm{thickness, ior}}
+
+    // Reflectance over a substrate of complex index eta + ik given per RGB
+    // channel. Spectral paths (lambda > 0) get the value at their wavelength
+    // in every channel.
+    pub fn reflectance(&self, rec:&HitRecord, cos_i:f64, n_inc:f64, eta:Vec3, k:Vec3, lambda:f64) -> Vec3{
+        let d = self.thickness.value(rec.u, rec.v, rec.p).x.max(0.0);
+        let n_film = self.ior.value(rec.u, rec.v, rec.p).x;
+        let at = |l:f64, e:f64, k:f64| airy_reflectance(cos_i, n_inc, n_film, Complex64::new(e, k), d, l);
+        if lambda > 0.0 {
+            return Vec3::ones() * at(lambda, rgb_at(eta, lambda), rgb_at(k, lambda));
+        }
+        vec3(at(RGB_WAVELENGTHS[0], eta.x, k.x), at(RGB_WAVELENGTHS[1], eta.y, k.y), at(RGB_WAVELENGTHS[2], eta.z, k.z))
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use crate::raymath::{Vec3, vec3, unit_vector, Ray3, Aabb, HitRay, HitRecord, SamplingCfg, ScatterResult, MaterialId, SplitMix, ray_seed};
+use crate::subsurface::sample_hg;
+
+// Heterogeneous participating media
+// A Volume is a box shaped primitive whose hit() runs delta tracking through
+// a voxel density grid. A "hit" is a real collision inside the medium, shaded
+// by a VolumeMaterial that either emits and absorbs or scatters the path.
+
+// Scalar voxel grid over an axis aligned box, x varying fastest
+#[derive(Debug)]
+pub struct DensityGrid{
+    pub nx : usize,
+    pub ny : usize,
+    pub nz : usize,
+    pub bounds : Aabb,
+    pub max : f64,
+    data : Vec<f32>
+}
+
+fn invalid(msg:&str) -> io::Error{io::Error::new(io::ErrorKind::InvalidData, msg.to_string())}
+
+fn read_u32(b:&[u8], o:usize) -> u32{u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]])}
+fn read_f32(b:&[u8], o:usize) -> f32{f32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]])}
+
+// Voxel count of a grid and the bytes it takes after a header of `offset`
+// bytes, checked against the file length
+fn voxel_count(nx:usize, ny:usize, nz:usize, offset:usize, len:usize) -> io::Result<usize>{
+    if nx == 0 || ny == 0 || nz == 0 {
+        return Err(invalid("empty voxel grid"));
+    }
+    let n = nx.checked_mul(ny).and_then(|n| n.checked_mul(nz)).ok_or_else(|| invalid("voxel grid too large"))?;
+    let size = n.checked_mul(4).and_then(|s| s.checked_add(offset)).ok_or_else(|| invalid("voxel grid too large"))?;
+    if len < size {
+        return Err(invalid("truncated voxel data"));
+    }
+    Ok(n)
+}
+
+impl DensityGrid{
+    pub fn new(nx:usize, ny:usize, nz:usize, bounds:Aabb, data:Vec<f32>) -> DensityGrid{
+        assert_eq!(data.len(), nx * ny * nz);
+        let max = data.iter().fold(0.0f32, |m, &d| m.max(d)) as f64;
+        DensityGrid{nx, ny, nz, bounds, max, data}
+    }
+
+    pub fn constant(bounds:Aabb, density:f64) -> DensityGrid{
+        DensityGrid::new(1, 1, 1, bounds, vec![density as f32])
+    }
+
+    pub fn from_fn(nx:usize, ny:usize, nz:usize, bounds:Aabb, f:impl Fn(Vec3) -> f64) -> DensityGrid{
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for z in 0 .. nz {
+            for y in 0 .. ny {
+                for x in 0 .. nx {
+                    let t = vec3((x as f64 + 0.5) / nx as f64, (y as f64 + 0.5) / ny as f64, (z as f64 + 0.5) / nz as f64);
+                    data.push(f(t).max(0.0) as f32);
+                }
+            }
+        }
+        DensityGrid::new(nx, ny, nz, bounds, data)
+    }
+
+    // Headerless little endian f32 voxels
+    pub fn load_raw(path:&str, nx:usize, ny:usize, nz:usize, bounds:Aabb) -> io::Result<DensityGrid>{
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let n = voxel_count(nx, ny, nz, 0, bytes.len())?;
+        let data = (0 .. n).map(|i| read_f32(&bytes, 4 * i).max(0.0)).collect();
+        Ok(DensityGrid::new(nx, ny, nz, bounds, data))
+    }
+
+    // Grid file: magic "DGRD", u32 nx ny nz, f32 bounds min xyz and max xyz,
+    // then the voxels as in load_raw. All little endian.
+    pub fn load(path:&str) -> io::Result<DensityGrid>{
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < 40 || &bytes[0 .. 4] != b"DGRD" {
+            return Err(invalid("not a density grid"));
+        }
+        let (nx, ny, nz) = (read_u32(&bytes, 4) as usize, read_u32(&bytes, 8) as usize, read_u32(&bytes, 12) as usize);
+        let f = |i:usize| read_f32(&bytes, 16 + 4 * i) as f64;
+        let bounds = Aabb::new(vec3(f(0), f(1), f(2)), vec3(f(3), f(4), f(5)));
+        if !(bounds.min.x < bounds.max.x && bounds.min.y < bounds.max.y && bounds.min.z < bounds.max.z) {
+            return Err(invalid("empty grid bounds"));
+        }
+        let n = voxel_count(nx, ny, nz, 40, bytes.len())?;
+        let data = (0 .. n).map(|i| read_f32(&bytes, 40 + 4 * i).max(0.0)).collect();
+        Ok(DensityGrid::new(nx, ny, nz, bounds, data))
+    }
+
+    fn voxel(&self, x:i64, y:i64, z:i64) -> f64{
+        let cx = x.clamp(0, self.nx as i64 - 1) as usize;
+        let cy = y.clamp(0, self.ny as i64 - 1) as usize;
+        let cz = z.clamp(0, self.nz as i64 - 1) as usize;
+        self.data[(cz * self.ny + cy) * self.nx + cx] as f64
+    }
+
+    // Trilinear lookup at a world space point, zero outside the bounds
+    pub fn value(&self, p:Vec3) -> f64{
+        let d = self.bounds.diagonal();
+        let rel = p - self.bounds.min;
+        let t = vec3(rel.x / d.x, rel.y / d.y, rel.z / d.z);
+        if t.x < 0.0 || t.y < 0.0 || t.z < 0.0 || t.x > 1.0 || t.y > 1.0 || t.z > 1.0 {
+            return 0.0;
+        }
+        let x = t.x * self.nx as f64 - 0.5;
+        let y = t.y * self.ny as f64 - 0.5;
+        let z = t.z * self.nz as f64 - 0.5;
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+        let lerp = |a:f64, b:f64, t:f64| a + (b - a) * t;
+        let plane = |z:i64| {
+            let lo = lerp(self.voxel(x0, y0, z), self.voxel(x0 + 1, y0, z), fx);
+            let hi = lerp(self.voxel(x0, y0 + 1, z), self.voxel(x0 + 1, y0 + 1, z), fx);
+            lerp(lo, hi, fy)
+        };
+        lerp(plane(z0), plane(z0 + 1), fz)
+    }
+}
+
+#[derive(Debug)]
+pub struct Volume{
+    pub density : Arc<DensityGrid>,
+    // Extinction coefficient per unit density
+    pub sigma_t : f64,
+    pub material : MaterialId
+}
+
+impl Volume{
+    pub fn new(density:Arc<DensityGrid>, sigma_t:f64, material:MaterialId) -> Volume{
+        Volume{density, sigma_t, material}
+    }
+
+    pub fn bounding_box(&self) -> Aabb{self.density.bounds}
+}
+
+impl HitRay for Volume{
+    // Delta tracking against the majorant max density * sigma_t
+    fn hit(&self, r:&Ray3, cfg:SamplingCfg) -> Option<HitRecord>{
+        let majorant = self.density.max * self.sigma_t;
+        if majorant <= 0.0 {
+            return None;
+        }
+        let (t0, t1) = self.density.bounds.hit(r, cfg.t_min, cfg.t_max)?;
+        let speed = r.dir.length();
+        // Seeded per ray so that a volume tested twice with the same ray (for
+        // example from two accelerator cells) reports the same collision
+        let mut rng = SplitMix(ray_seed(r) ^ self.material as u64);
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rng.next_f64()).ln() / (majorant * speed);
+            if t >= t1 {
+                return None;
+            }
+            let p = r.at(t);
+            if rng.next_f64() * self.density.max < self.density.value(p) {
+                let mut rec = HitRecord::new_default(self.material);
+                rec.t = t;
+                rec.p = p;
+                rec.normal = unit_vector(r.dir) * -1.0;
+                rec.front_face = true;
+                return Some(rec);
+            }
+        }
+    }
+}
+
+// Phase function and emission of a medium. At a collision the path absorbs
+// (and collects emission) with probability 1 - albedo and scatters otherwise;
+// both are folded into the path weight.
+#[derive(Debug)]
+pub struct VolumeMaterial{
+    pub albedo : Vec3,
+    // Henyey-Greenstein anisotropy
+    pub g : f64,
+    pub emission : Vec3,
+    // Scales the emission per point, for example a temperature grid
+    pub emission_grid : Option<Arc<DensityGrid>>
+}
+
+impl VolumeMaterial{
+    pub fn new(albedo:Vec3, g:f64) -> VolumeMaterial{
+        VolumeMaterial{albedo, g, emission:Vec3::zeros(), emission_grid:None}
+    }
+    pub fn with_emission(self, emission:Vec3, grid:Option<Arc<DensityGrid>>) -> VolumeMaterial{
+        VolumeMaterial{emission, emission_grid:grid, ..self}
+    }
+
+    pub fn emitted(&self, rec:&HitRecord) -> Vec3{
+        let scale = match &self.emission_grid {
+            Some(grid) => grid.value(rec.p),
+            None => 1.0
+        };
+        (Vec3::ones() - self.albedo).mul_elements(self.emission) * scale
+    }
+
+    pub fn scatter(&self, r_in:Ray3, rec:HitRecord) -> Option<ScatterResult>{
+        let scattered = Ray3::new(rec.p, sample_hg(r_in.dir, self.g)).with_lambda(r_in.lambda);
+        Some(ScatterResult{attenuation:self.albedo, scattered})
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::fs;
+
+    fn grid_file(dims:[u32; 3], bounds:[f32; 6], voxels:&[f32]) -> Vec<u8>{
+        let mut bytes = b"DGRD".to_vec();
+        bytes.extend(dims.iter().flat_map(|d| d.to_le_bytes()));
+        bytes.extend(bounds.iter().flat_map(|b| b.to_le_bytes()));
+        bytes.extend(voxels.iter().flat_map(|v| v.to_le_bytes()));
+        bytes
+    }
+
+    fn load_bytes(name:&str, bytes:&[u8]) -> io::Result<DensityGrid>{
+        let path = std::env::temp_dir().join(format!("wknd-grid-{}-{}.dgrd", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let grid = DensityGrid::load(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        grid
+    }
+
+    fn error(name:&str, bytes:&[u8]) -> String{
+        let e = load_bytes(name, bytes).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.to_string()
+    }
+
+    const UNIT : [f32; 6] = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+
+    #[test]
+    fn loads_a_grid_file(){
+        let voxels = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, -7.0];
+        let grid = load_bytes("ok", &grid_file([2, 2, 2], UNIT, &voxels)).unwrap();
+        assert_eq!((grid.nx, grid.ny, grid.nz), (2, 2, 2));
+        assert_eq!(grid.bounds.max, vec3(1.0, 1.0, 1.0));
+        assert_eq!(grid.max, 6.0);
+        // Negative densities are clamped, x varies fastest
+        assert_eq!(grid.voxel(1, 1, 1), 0.0);
+        assert_eq!(grid.voxel(1, 0, 0), 1.0);
+        assert_eq!(grid.voxel(0, 1, 1), 6.0);
+    }
+
+    #[test]
+    fn corrupt_grid_files_are_rejected(){
+        assert_eq!(error("magic", &b"DGRX".repeat(10)), "not a density grid");
+        assert_eq!(error("short", &grid_file([1, 1, 1], UNIT, &[])[.. 20]), "not a density grid");
+        assert_eq!(error("empty", &grid_file([0, 2, 2], UNIT, &[])), "empty voxel grid");
+        assert_eq!(error("flat", &grid_file([1, 1, 1], [0.0, 0.0, 0.0, 1.0, 0.0, 1.0], &[1.0])), "empty grid bounds");
+        assert_eq!(error("truncated", &grid_file([2, 2, 2], UNIT, &[1.0; 7])), "truncated voxel data");
+        // The voxel count overflows usize
+        let huge = [u32::MAX, u32::MAX, u32::MAX];
+        assert_eq!(error("huge", &grid_file(huge, UNIT, &[1.0])), "voxel grid too large");
+    }
+}
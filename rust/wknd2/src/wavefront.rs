@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::mem::discriminant;
+use crate::Cfg;
+use crate::integrator::{PathState, ShadowRay, WavefrontCfg, RaySort, extension_cfg, occluded};
+use crate::raymath::{Vec3, Camera, HitRay, HitRecord, Scene, random_f64_normalized, unit_vector, write_color_to_buf};
+
+// Wavefront integrator
+// Instead of tracing one path to completion, a queue of path states is
+// advanced one stage at a time: all extension rays, then all material
+// evaluations grouped by material variant, then all shadow rays. Finished
+// paths are retired and their slots refilled with new camera rays.
+
+// Interleave the low 10 bits of each coordinate.
+fn morton3(x:u32, y:u32, z:u32) -> u32{
+    let spread = |v:u32| {
+        let mut v = v & 0x3ff;
+        v = (v | (v << 16)) & 0x030000ff;
+        v = (v | (v << 8)) & 0x0300f00f;
+        v = (v | (v << 4)) & 0x030c30c3;
+        v = (v | (v << 2)) & 0x09249249;
+        v
+    };
+    spread(x) | (spread(y) << 1) | (spread(z) << 2)
+}
+
+fn quantize(t:f64) -> u32{
+    (t.clamp(0.0, 1.0) * 1023.0) as u32
+}
+
+fn sort_rays(queue:&mut [PathState], sort:RaySort){
+    match sort {
+        RaySort::None => {},
+        RaySort::Direction => {
+            queue.sort_by_cached_key(|p| {
+                let d = unit_vector(p.ray.dir);
+                morton3(quantize(0.5 * (d.x + 1.0)), quantize(0.5 * (d.y + 1.0)), quantize(0.5 * (d.z + 1.0)))
+            });
+        },
+        RaySort::Origin => {
+            let mut lo = Vec3{x:f64::MAX, y:f64::MAX, z:f64::MAX};
+            let mut hi = Vec3{x:f64::MIN, y:f64::MIN, z:f64::MIN};
+            for p in queue.iter() {
+                let o = p.ray.orig;
+                lo = Vec3{x:lo.x.min(o.x), y:lo.y.min(o.y), z:lo.z.min(o.z)};
+                hi = Vec3{x:hi.x.max(o.x), y:hi.y.max(o.y), z:hi.z.max(o.z)};
+            }
+            let ext = hi - lo;
+            let rel = |v:f64, l:f64, e:f64| if e > 0.0 {(v - l) / e} else {0.0};
+            queue.sort_by_cached_key(|p| {
+                let o = p.ray.orig;
+                morton3(quantize(rel(o.x, lo.x, ext.x)), quantize(rel(o.y, lo.y, ext.y)), quantize(rel(o.z, lo.z, ext.z)))
+            });
+        }
+    }
+}
+
+pub fn render_line(pixels:&mut [i32], cfg: &Cfg, cam:&Camera, scene:&Scene, y:i32, opts:&WavefrontCfg){
+    let fj = (cfg.image_height - y -1) as f64;
+    let f_w = (cfg.image_width - 1) as f64;
+    let f_h = (cfg.image_height -1) as f64;
+    let spp = cfg.samples_per_pixel as usize;
+    let total = cfg.image_width as usize * spp;
+    let queue_size = opts.queue_size.max(1);
+
+    let mut accum = vec![Vec3::zeros(); cfg.image_width as usize];
+    let mut queue : Vec<PathState> = Vec::with_capacity(queue_size);
+    let mut shadows : Vec<ShadowRay> = vec![];
+    let mut shadow_owners : Vec<usize> = vec![];
+    let mut next = 0;
+    loop {
+        // Refill finished slots with camera rays
+        while queue.len() < queue_size && next < total {
+            let i = next / spp;
+            let u = (i as f64 + random_f64_normalized()) / f_w;
+            let v = (fj + random_f64_normalized()) / f_h;
+            queue.push(PathState::new(cam.get_ray(u, v), cfg.max_depth, i));
+            next += 1;
+        }
+        if queue.is_empty() {
+            break;
+        }
+
+        sort_rays(&mut queue, opts.sort);
+
+        // Extension rays
+        let hits : Vec<Option<HitRecord>> = queue.iter().map(|p| scene.world.hit(&p.ray, extension_cfg(scene))).collect();
+
+        // Material evaluation, one material variant at a time. Misses form their own group.
+        let mut groups = HashMap::new();
+        for (k, hit) in hits.iter().enumerate() {
+            let key = hit.and_then(|h| scene.mats.get(h.mat)).map(discriminant);
+            groups.entry(key).or_insert_with(Vec::new).push(k);
+        }
+        for group in groups.values() {
+            for &k in group {
+                queue[k].shade(hits[k], scene, &mut shadows);
+                shadow_owners.resize(shadows.len(), k);
+            }
+        }
+
+        // Shadow rays
+        for (shadow, &k) in shadows.iter().zip(shadow_owners.iter()) {
+            if !occluded(scene, shadow) {
+                queue[k].radiance = queue[k].radiance + shadow.contribution;
+            }
+        }
+        shadows.clear();
+        shadow_owners.clear();
+
+        // Retire finished paths
+        queue.retain(|p| {
+            if !p.alive {
+                accum[p.pixel] = accum[p.pixel] + p.color();
+            }
+            p.alive
+        });
+    }
+
+    for (idx, col) in accum.iter().enumerate() {
+        write_color_to_buf(pixels, idx, *col, cfg.samples_per_pixel);
+    }
+}